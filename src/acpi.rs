@@ -0,0 +1,211 @@
+//! Just enough ACPI table parsing to find the processors in the system.
+//!
+//! The tables are read through the bootloader's physical memory mapping, so
+//! nothing needs to be mapped first.
+
+use alloc::vec::Vec;
+use core::{fmt, ptr, slice};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The signature at the start of the Root System Description Pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The size of the header shared by every System Description Table.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Errors that can occur while looking for the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// There's no RSDP in the BIOS areas it's supposed to be in.
+    RsdpNotFound,
+    /// The table with this signature failed its checksum.
+    BadChecksum([u8; 4]),
+    /// The table with this signature is shorter than its own header.
+    TooShort([u8; 4]),
+    /// The RSDT doesn't list a MADT.
+    MadtNotFound,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::RsdpNotFound => write!(f, "no ACPI RSDP found"),
+            AcpiError::BadChecksum(signature) => write!(
+                f,
+                "ACPI table {} has a bad checksum",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            AcpiError::TooShort(signature) => write!(
+                f,
+                "ACPI table {} is too short",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            AcpiError::MadtNotFound => write!(f, "no ACPI MADT found"),
+        }
+    }
+}
+
+/// A processor listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// The processor's ID in the ACPI namespace.
+    pub processor_id: u8,
+    /// The ID of the processor's local APIC, used to send it IPIs.
+    pub apic_id: u8,
+}
+
+/// The parts of the Multiple APIC Description Table we care about.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// The physical address of the local APIC registers.
+    pub local_apic_address: PhysAddr,
+    /// Every processor that is enabled or can be brought online.
+    pub processors: Vec<Processor>,
+}
+
+/// Find and parse the MADT.
+///
+/// # Safety
+/// The complete physical memory must be mapped at `physical_memory_offset`.
+pub unsafe fn parse_madt(physical_memory_offset: VirtAddr) -> Result<Madt, AcpiError> {
+    let memory = PhysicalMemory(physical_memory_offset);
+
+    let rsdp = find_rsdp(&memory).ok_or(AcpiError::RsdpNotFound)?;
+    let madt = find_table(&memory, rsdp, b"APIC")?.ok_or(AcpiError::MadtNotFound)?;
+
+    let length = memory.read::<u32>(madt + 4u64) as u64;
+    let mut local_apic_address = PhysAddr::new(memory.read::<u32>(madt + SDT_HEADER_SIZE) as u64);
+    let mut processors = Vec::new();
+
+    // The entries start after the local APIC address and the flags.
+    let mut entry = madt + SDT_HEADER_SIZE + 8u64;
+    while entry + 2u64 <= madt + length {
+        let entry_type = memory.read::<u8>(entry);
+        let entry_length = memory.read::<u8>(entry + 1u64);
+        if entry_length < 2 {
+            break;
+        }
+
+        match entry_type {
+            // Processor Local APIC
+            0 => {
+                let flags = memory.read::<u32>(entry + 4u64);
+                let enabled = flags & 0b1 != 0;
+                let online_capable = flags & 0b10 != 0;
+
+                if enabled || online_capable {
+                    processors.push(Processor {
+                        processor_id: memory.read(entry + 2u64),
+                        apic_id: memory.read(entry + 3u64),
+                    });
+                }
+            }
+            // Local APIC Address Override
+            5 => local_apic_address = PhysAddr::new(memory.read(entry + 4u64)),
+            _ => {}
+        }
+
+        entry += entry_length as u64;
+    }
+
+    Ok(Madt {
+        local_apic_address,
+        processors,
+    })
+}
+
+/// Physical memory, accessed through the bootloader's mapping.
+struct PhysicalMemory(VirtAddr);
+
+impl PhysicalMemory {
+    /// Read a possibly unaligned value.
+    unsafe fn read<T: Copy>(&self, addr: PhysAddr) -> T {
+        ptr::read_unaligned((self.0 + addr.as_u64()).as_ptr())
+    }
+
+    unsafe fn bytes(&self, addr: PhysAddr, len: usize) -> &[u8] {
+        slice::from_raw_parts((self.0 + addr.as_u64()).as_ptr(), len)
+    }
+
+    /// Returns `true` if the `len` bytes at `addr` add up to zero, like every
+    /// ACPI structure's bytes should.
+    unsafe fn checksum_ok(&self, addr: PhysAddr, len: usize) -> bool {
+        let sum = self
+            .bytes(addr, len)
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        sum == 0
+    }
+}
+
+/// Look for the RSDP in the first KiB of the Extended BIOS Data Area and in
+/// the BIOS ROM area.
+unsafe fn find_rsdp(memory: &PhysicalMemory) -> Option<PhysAddr> {
+    // The real mode segment of the EBDA is stored in the BIOS Data Area.
+    let ebda = (memory.read::<u16>(PhysAddr::new(0x40e)) as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+
+    areas
+        .iter()
+        .filter(|(start, _)| *start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            memory.bytes(addr, RSDP_SIGNATURE.len()) == RSDP_SIGNATURE
+                && memory.checksum_ok(addr, 20)
+        })
+}
+
+/// Find the table with the given signature through the RSDT, or the XSDT if
+/// there is one.
+unsafe fn find_table(
+    memory: &PhysicalMemory,
+    rsdp: PhysAddr,
+    signature: &[u8; 4],
+) -> Result<Option<PhysAddr>, AcpiError> {
+    let revision = memory.read::<u8>(rsdp + 15u64);
+    let xsdt = if revision >= 2 {
+        memory.read::<u64>(rsdp + 24u64)
+    } else {
+        0
+    };
+
+    let (root, entry_size) = if xsdt != 0 {
+        (PhysAddr::new(xsdt), 8)
+    } else {
+        (PhysAddr::new(memory.read::<u32>(rsdp + 16u64) as u64), 4)
+    };
+    check_table(memory, root)?;
+
+    let length = memory.read::<u32>(root + 4u64) as usize;
+    let entries = length
+        .checked_sub(SDT_HEADER_SIZE)
+        .ok_or_else(|| AcpiError::TooShort(memory.read(root)))?
+        / entry_size;
+
+    for i in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + i * entry_size;
+        let table = if entry_size == 8 {
+            PhysAddr::new(memory.read::<u64>(entry))
+        } else {
+            PhysAddr::new(memory.read::<u32>(entry) as u64)
+        };
+
+        if memory.bytes(table, 4) == signature {
+            check_table(memory, table)?;
+            return Ok(Some(table));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Verify the checksum of the System Description Table at `table`.
+unsafe fn check_table(memory: &PhysicalMemory, table: PhysAddr) -> Result<(), AcpiError> {
+    let length = memory.read::<u32>(table + 4u64) as usize;
+
+    if memory.checksum_ok(table, length) {
+        Ok(())
+    } else {
+        Err(AcpiError::BadChecksum(memory.read(table)))
+    }
+}
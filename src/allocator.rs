@@ -2,6 +2,7 @@ pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
+use crate::lock::{IrqSafeMutex, IrqSafeMutexGuard};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 use fixed_size_block::FixedSizeBlockAllocator;
@@ -48,6 +49,11 @@ pub fn init_heap(
     Ok(())
 }
 
+/// The number of bytes of the heap that are in use.
+pub fn heap_used() -> usize {
+    ALLOCATOR.lock().used()
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 ///
 /// Requires that `align` is a power of two.
@@ -55,19 +61,23 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
-/// A wrapper around `spin::Mutex` to permit trait implementations.
+/// A wrapper around `IrqSafeMutex` to permit trait implementations.
+///
+/// Since the lock disables interrupts, interrupt handlers can allocate
+/// without deadlocking with the code they interrupted.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn lock(&self) -> IrqSafeMutexGuard<A> {
         self.inner.lock()
     }
 }
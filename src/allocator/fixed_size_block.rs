@@ -23,6 +23,11 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// The number of bytes that an allocation with the given layout takes up.
+fn allocation_size(layout: &Layout) -> usize {
+    list_index(layout).map_or(layout.size(), |index| BLOCK_SIZES[index])
+}
+
 /// A node in a linked list.
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -39,6 +44,8 @@ struct ListNode {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// The bytes handed out, counting whole blocks.
+    used: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -47,6 +54,7 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [None; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            used: 0,
         }
     }
 
@@ -60,6 +68,12 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// The number of bytes in use, counting the whole block for allocations
+    /// that got one.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
@@ -73,7 +87,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
@@ -95,11 +109,17 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
 
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.used += allocation_size(&layout);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.used -= allocation_size(&layout);
 
         match list_index(&layout) {
             Some(index) => {
@@ -0,0 +1,133 @@
+//! The local APIC, which every CPU has one of.
+//!
+//! We only use it to start and interrupt other CPUs for now. Device
+//! interrupts still go through the 8259 PICs to the bootstrap processor.
+
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The interrupt vector the local APIC uses for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The interrupt vector of the IPI that gets a halted CPU out of `hlt`.
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+
+/// The virtual address the local APIC registers are mapped at.
+const LOCAL_APIC_START: u64 = 0x_5555_0000_0000;
+
+// Register offsets.
+const ID: usize = 0x20;
+const EOI: usize = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xf0;
+const ERROR_STATUS: usize = 0x280;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+
+/// Set in the spurious interrupt vector register to enable the APIC.
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+/// Set in the interrupt command register while an IPI is being sent.
+const DELIVERY_PENDING: u32 = 1 << 12;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+/// Map the local APIC registers at `address`.
+///
+/// Every CPU sees its own local APIC at the same address, so this only has to
+/// be done once, on the bootstrap processor.
+pub fn init(
+    address: PhysAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = PhysFrame::containing_address(address);
+    let page = Page::containing_address(VirtAddr::new(LOCAL_APIC_START));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+
+    let base = page.start_address() + (address.as_u64() - frame.start_address().as_u64());
+    LOCAL_APIC.init_once(|| LocalApic { base });
+    Ok(())
+}
+
+/// Get the current CPU's local APIC.
+///
+/// Returns `None` if `apic::init` hasn't been called yet.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.try_get().ok()
+}
+
+/// The local APIC of whichever CPU accesses it.
+#[derive(Debug)]
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr(), value) }
+    }
+
+    /// The ID of the current CPU's local APIC.
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Enable the current CPU's local APIC, so that it accepts IPIs.
+    pub fn enable(&self) {
+        self.write(
+            SPURIOUS_INTERRUPT_VECTOR,
+            APIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+
+    /// Signal the end of an interrupt that came through the local APIC.
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    /// Send an INIT IPI, which resets the target CPU into a waiting state.
+    pub fn send_init(&self, apic_id: u8) {
+        // Level triggered, assert, INIT delivery mode.
+        self.send_ipi(apic_id, 0x0000_c500);
+    }
+
+    /// Send a STARTUP IPI, which starts a waiting CPU in real mode at physical
+    /// address `page << 12`.
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_ipi(apic_id, 0x0000_0600 | u32::from(page));
+    }
+
+    /// Send a fixed interrupt with the given vector to another CPU.
+    pub fn send_interrupt(&self, apic_id: u8, vector: u8) {
+        self.send_ipi(apic_id, u32::from(vector));
+    }
+
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        use x86_64::instructions::interrupts;
+
+        // An interrupt handler sending its own IPI in between the two writes
+        // would change our destination.
+        interrupts::without_interrupts(|| {
+            self.write(ERROR_STATUS, 0);
+            self.write(INTERRUPT_COMMAND_HIGH, u32::from(apic_id) << 24);
+            // Writing the low half sends the IPI.
+            self.write(INTERRUPT_COMMAND_LOW, command);
+
+            while self.read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {}
+        });
+    }
+}
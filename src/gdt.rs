@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::{
     structures::{
@@ -14,25 +15,15 @@ lazy_static! {
     /// A global Task State Segment that contains a seperate Double Fault stack
     /// in its interrupt stack table.
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-
-            stack_start + STACK_SIZE
-        };
-        tss
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        new_tss(stack_start + STACK_SIZE)
     };
 
     /// Our kernel's Global Descriptor Table.
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
 /// Various code segment selectors.
@@ -41,14 +32,58 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-/// Initialize the Global Descriptor Table.
-pub fn init() {
+/// Create a Task State Segment whose Double Fault stack ends at
+/// `double_fault_stack_end`.
+fn new_tss(double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
+
+/// Create a Global Descriptor Table with a kernel code segment and `tss`.
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+        },
+    )
+}
+
+/// Load `gdt` and its segments on the current CPU.
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::{segmentation::set_cs, tables::load_tss};
 
-    GDT.0.load();
+    gdt.0.load();
 
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
+
+/// Initialize the Global Descriptor Table.
+pub fn init() {
+    load(&*GDT);
+}
+
+/// The bootstrap processor's Task State Segment.
+pub(crate) fn tss() -> &'static TaskStateSegment {
+    &TSS
+}
+
+/// Give an application processor its own Task State Segment and Global
+/// Descriptor Table and load them, returning the TSS.
+///
+/// CPUs can't share a TSS, because loading it marks it as busy. The tables
+/// are leaked, since CPUs never go away.
+pub fn init_ap(double_fault_stack_end: VirtAddr) -> &'static TaskStateSegment {
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(new_tss(double_fault_stack_end)));
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+    load(gdt);
+    tss
+}
@@ -1,4 +1,5 @@
-use crate::{gdt, hlt_loop, print, println};
+use crate::{apic, gdt, hlt_loop, lock::IrqSafeMutex, percpu, println};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
@@ -6,8 +7,11 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// The number of timer interrupts since the PICs were initialized.
+static TICKS: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -18,6 +22,10 @@ lazy_static! {
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[usize::from(apic::WAKEUP_VECTOR)].set_handler_fn(wakeup_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
 
         unsafe {
             idt.double_fault
@@ -35,6 +43,10 @@ lazy_static! {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ 4, the first serial port.
+    Serial = PIC_1_OFFSET + 4,
+    /// IRQ 12, on the secondary PIC.
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -56,6 +68,62 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Unmask the PIC line of `index`, and the line the secondary PIC is chained
+/// to if it's on that one. The firmware might have left them masked.
+pub(crate) fn unmask(index: InterruptIndex) {
+    use x86_64::instructions::port::Port;
+
+    let mut primary = Port::<u8>::new(0x21);
+    let mut secondary = Port::<u8>::new(0xa1);
+    let line = index.as_u8() - PIC_1_OFFSET;
+
+    // Keep anyone else from touching the PICs in between.
+    let _pics = PICS.lock();
+    unsafe {
+        if line < 8 {
+            let mask = primary.read();
+            primary.write(mask & !(1 << line));
+        } else {
+            let mask = secondary.read();
+            secondary.write(mask & !(1 << (line - 8)));
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+        }
+    }
+}
+
+/// The number of timer interrupts since the PICs were initialized.
+///
+/// The PIT is left at its default rate, so one tick is roughly 55ms.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the current CPU is running inside a hardware interrupt
+/// handler.
+pub fn in_interrupt() -> bool {
+    percpu::current().interrupt_depth.load(Ordering::Relaxed) > 0
+}
+
+/// Marks the current code as running in interrupt context until it's dropped.
+struct InterruptContext;
+
+impl InterruptContext {
+    fn enter() -> Self {
+        let cpu = percpu::current();
+        cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        cpu.stats().count_interrupt();
+        InterruptContext
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        let cpu = percpu::current();
+        cpu.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// Sends an "End of Interrupt" signal to the POI.
 fn send_eoi_signal(interrupt_index: InterruptIndex) {
     unsafe {
@@ -91,14 +159,25 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
+    {
+        let _context = InterruptContext::enter();
+
+        TICKS.fetch_add(1, Ordering::Relaxed);
+
+        send_eoi_signal(InterruptIndex::Timer);
+    }
 
-    send_eoi_signal(InterruptIndex::Timer);
+    // This might switch to another thread, so we must be done with the PIC
+    // and out of interrupt context first. Interrupts stay disabled until the
+    // thread we switch to re-enables them.
+    crate::thread::preempt();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
+    let _context = InterruptContext::enter();
+
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
@@ -107,6 +186,49 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
     send_eoi_signal(InterruptIndex::Keyboard);
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let _context = InterruptContext::enter();
+
+    // The data and line status registers of the first serial port.
+    let mut data = Port::<u8>::new(0x3f8);
+    let mut line_status = Port::<u8>::new(0x3fd);
+    // Bit 0 is set while there's received data.
+    while unsafe { line_status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        crate::task::serial::add_byte(byte);
+    }
+
+    send_eoi_signal(InterruptIndex::Serial);
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let _context = InterruptContext::enter();
+
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+
+    crate::task::mouse::add_byte(byte);
+
+    send_eoi_signal(InterruptIndex::Mouse);
+}
+
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+
+    // Nothing else to do, the interrupt only exists to end a `hlt`.
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    // Spurious interrupts don't get an EOI.
+}
+
 #[cfg(test)]
 mod tests {
     #[test_case]
@@ -2,9 +2,11 @@
 #![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
 #![feature(const_in_array_repeat_expressions)]
 #![feature(const_mut_refs)]
 #![feature(custom_test_frameworks)]
+#![feature(global_asm)]
 #![feature(wake_trait)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -16,19 +18,32 @@ use core::panic::PanicInfo;
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
 pub mod gdt;
 pub mod interrupts;
+pub mod lock;
 pub mod memory;
+pub mod percpu;
+pub mod power;
+pub mod ps2;
 pub mod serial;
+pub mod shell;
+pub mod smp;
 pub mod task;
+pub mod thread;
 pub mod vga_buffer;
 
 /// Initialize the kernel.
 pub fn init() {
     gdt::init();
+    percpu::init_bsp(gdt::tss());
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    if let Err(error) = ps2::init() {
+        println!("{}; there won't be any keyboard input", error);
+    }
     x86_64::instructions::interrupts::enable();
 }
 
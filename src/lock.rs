@@ -0,0 +1,203 @@
+//! Spinlocks that can be shared with interrupt handlers.
+//!
+//! A `spin::Mutex` that an interrupt handler also locks deadlocks as soon as
+//! the interrupt arrives while the same CPU holds the lock: the handler spins
+//! forever, waiting for code that only continues once the handler returns.
+//! `IrqSafeMutex` disables interrupts for as long as it's locked and restores
+//! the previous state afterwards, so callers don't have to remember to wrap
+//! every lock in `without_interrupts`.
+//!
+//! Building with the `lock-debug` feature turns on the checks in the `debug`
+//! module.
+
+#[cfg(feature = "lock-debug")]
+mod debug;
+
+#[cfg(feature = "lock-debug")]
+use core::panic::Location;
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use x86_64::instructions::interrupts;
+
+/// A spinlock that keeps interrupts disabled while it's locked.
+///
+/// Guards restore the interrupt flag they found when they're dropped, so
+/// nested guards must be dropped in reverse order. Otherwise interrupts come
+/// back on while the inner lock is still held.
+pub struct IrqSafeMutex<T: ?Sized> {
+    #[cfg(feature = "lock-debug")]
+    debug: debug::LockInfo,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Create a new unlocked mutex.
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            #[cfg(feature = "lock-debug")]
+            debug: debug::LockInfo::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// Consume the mutex, returning the value inside.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// Disable interrupts and lock the mutex, spinning until it's available.
+    ///
+    /// # Panics
+    /// With the `lock-debug` feature, panics if the current CPU already holds
+    /// the lock, which would deadlock.
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(feature = "lock-debug")]
+        {
+            if let Err(error) = self.debug.check(Location::caller()) {
+                // Nobody else gets to release the lock anymore, so free it for
+                // whoever prints the panic message.
+                unsafe { self.inner.force_unlock() };
+                panic!("{}", error);
+            }
+        }
+
+        let guard = self.inner.lock();
+
+        #[cfg(feature = "lock-debug")]
+        self.debug.locked(Location::caller());
+
+        IrqSafeMutexGuard {
+            #[cfg(feature = "lock-debug")]
+            debug: &self.debug,
+            guard: ManuallyDrop::new(guard),
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Try to lock the mutex without spinning. Returns `None` if it's locked.
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lock-debug")]
+                self.debug.locked(Location::caller());
+
+                Some(IrqSafeMutexGuard {
+                    #[cfg(feature = "lock-debug")]
+                    debug: &self.debug,
+                    guard: ManuallyDrop::new(guard),
+                    interrupts_were_enabled,
+                })
+            }
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T: Default> Default for IrqSafeMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Gives access to the value inside an `IrqSafeMutex` until it's dropped,
+/// which unlocks the mutex and restores the interrupt flag.
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    #[cfg(feature = "lock-debug")]
+    debug: &'a debug::LockInfo,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Forget about the owner before someone else can become the owner.
+        #[cfg(feature = "lock-debug")]
+        self.debug.unlocked();
+
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn lock_disables_interrupts() {
+        let mutex = IrqSafeMutex::new(0);
+        assert!(interrupts::are_enabled());
+
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+        }
+
+        assert!(interrupts::are_enabled());
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn nested_locks_restore_interrupt_flag() {
+        let outer = IrqSafeMutex::new(());
+        let inner = IrqSafeMutex::new(());
+
+        let outer_guard = outer.lock();
+        let inner_guard = inner.lock();
+        drop(inner_guard);
+        // Still held by the outer guard.
+        assert!(!interrupts::are_enabled());
+        drop(outer_guard);
+
+        assert!(interrupts::are_enabled());
+    }
+
+    #[test_case]
+    fn try_lock_fails_while_locked() {
+        let mutex = IrqSafeMutex::new(());
+
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        // A failed `try_lock` leaves the interrupt flag alone.
+        assert!(!interrupts::are_enabled());
+        drop(guard);
+
+        assert!(mutex.try_lock().is_some());
+        assert!(interrupts::are_enabled());
+    }
+}
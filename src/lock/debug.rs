@@ -0,0 +1,293 @@
+//! Lock debugging, turned on by the `lock-debug` feature.
+//!
+//! Every `IrqSafeMutex` remembers which CPU holds it and where it was locked,
+//! and every CPU keeps a stack of the locks it's holding. That catches two
+//! kinds of bugs:
+//!
+//! - Locking a mutex that the current CPU already holds. That's usually an
+//!   interrupt handler locking something the code it interrupted holds, like
+//!   a `println!` in a handler that interrupted another `println!`. It's a
+//!   guaranteed deadlock, so `IrqSafeMutex::lock` panics instead.
+//! - Locking two mutexes in one order in one place and in the opposite order
+//!   in another, which deadlocks once two CPUs do both at the same time. The
+//!   first time an inversion shows up, it gets reported on the serial port.
+//!
+//! Everything is kept in fixed-size tables, because the heap allocator's lock
+//! is tracked as well. Only the first `MAX_LOCKS` mutexes that get locked take
+//! part in the lock order checks.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt::{self, Write},
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use uart_16550::SerialPort;
+
+/// The number of CPUs that get their held locks tracked, by APIC ID.
+const MAX_CPUS: usize = 16;
+/// The number of locks that take part in the lock order checks.
+const MAX_LOCKS: usize = 64;
+/// The number of locks per CPU that get tracked while they're held at once.
+const MAX_HELD: usize = 8;
+
+/// `LockInfo::id` of a lock that hasn't been locked yet.
+const NO_ID: usize = 0;
+/// `LockInfo::id` of a lock that got locked after all IDs were handed out.
+const UNTRACKED: usize = usize::MAX;
+
+/// `LockInfo::owner` of a lock that nobody holds.
+const NO_OWNER: usize = usize::MAX;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Bit `b` of `ORDER[a - 1]` is set once lock `b` was locked while lock `a`
+/// was held, both by ID.
+static ORDER: [AtomicU64; MAX_LOCKS] = [NO_LOCKS; MAX_LOCKS];
+/// The inversions in `ORDER` that have been reported already.
+static REPORTED: [AtomicU64; MAX_LOCKS] = [NO_LOCKS; MAX_LOCKS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_HOLDING: HeldLocks = HeldLocks::new();
+
+/// The locks each CPU is holding, by APIC ID.
+static HELD: [HeldLocks; MAX_CPUS] = [NOT_HOLDING; MAX_CPUS];
+
+/// Get the initial APIC ID of the current CPU.
+///
+/// Unlike `percpu::current`, this works on every CPU from the very start.
+fn cpu_id() -> usize {
+    // Bits 24..32 of EBX hold the initial APIC ID.
+    (unsafe { __cpuid(1) }.ebx >> 24) as usize
+}
+
+/// Write a report straight to the serial port.
+///
+/// `serial::SERIAL1` might be one of the locks involved, so this uses a port
+/// of its own.
+fn report(args: fmt::Arguments) {
+    let mut port = unsafe { SerialPort::new(0x3F8) };
+    let _ = port.write_fmt(args);
+}
+
+/// The debugging state of a single `IrqSafeMutex`.
+pub(super) struct LockInfo {
+    /// The ID used for the lock order checks, handed out the first time the
+    /// lock is locked.
+    id: AtomicUsize,
+    /// The APIC ID of the CPU holding the lock, or `NO_OWNER`.
+    owner: AtomicUsize,
+    /// Where the lock was locked most recently.
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl LockInfo {
+    pub(super) const fn new() -> Self {
+        LockInfo {
+            id: AtomicUsize::new(NO_ID),
+            owner: AtomicUsize::new(NO_OWNER),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Get the lock's ID, handing one out if needed. Returns `None` if the lock
+    /// doesn't take part in the lock order checks.
+    fn id(&self) -> Option<usize> {
+        let mut id = self.id.load(Ordering::Relaxed);
+
+        if id == NO_ID {
+            let new_id = match NEXT_ID.fetch_add(1, Ordering::Relaxed) {
+                new_id if new_id <= MAX_LOCKS => new_id,
+                _ => UNTRACKED,
+            };
+            id = match self
+                .id
+                .compare_exchange(NO_ID, new_id, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => new_id,
+                // Somebody else was faster. Our ID goes to waste.
+                Err(existing) => existing,
+            };
+        }
+
+        if id == UNTRACKED {
+            None
+        } else {
+            Some(id)
+        }
+    }
+
+    /// Where the lock was locked most recently, if it ever was.
+    fn location(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.location.load(Ordering::Relaxed).as_ref() }
+    }
+
+    /// Check that the current CPU can lock the lock at `location` without
+    /// deadlocking, and record the order it's locked in relative to the other
+    /// locks the CPU holds.
+    ///
+    /// Must be called with interrupts disabled, right before locking.
+    pub(super) fn check(&self, location: &'static Location<'static>) -> Result<(), Reentrant> {
+        let cpu = cpu_id();
+
+        if self.owner.load(Ordering::Acquire) == cpu {
+            return Err(Reentrant {
+                cpu,
+                first: self.location(),
+                second: location,
+            });
+        }
+
+        if let (Some(id), Some(held)) = (self.id(), HELD.get(cpu)) {
+            for other in held.iter() {
+                check_order(other, id, location);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record that the current CPU just locked the lock at `location`.
+    pub(super) fn locked(&self, location: &'static Location<'static>) {
+        let cpu = cpu_id();
+
+        self.location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.owner.store(cpu, Ordering::Release);
+
+        if let Some(held) = HELD.get(cpu) {
+            held.push(self);
+        }
+    }
+
+    /// Record that the current CPU is about to unlock the lock.
+    pub(super) fn unlocked(&self) {
+        if let Some(held) = HELD.get(cpu_id()) {
+            held.remove(self);
+        }
+
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/// Record that the lock with ID `id` is being locked at `location` while
+/// `held` is held, and report it if they were locked the other way around
+/// before.
+fn check_order(held: &LockInfo, id: usize, location: &'static Location<'static>) {
+    let held_id = match held.id() {
+        Some(held_id) => held_id,
+        None => return,
+    };
+
+    ORDER[held_id - 1].fetch_or(1 << (id - 1), Ordering::Relaxed);
+
+    let inverted = ORDER[id - 1].load(Ordering::Relaxed) & 1 << (held_id - 1) != 0;
+    if inverted {
+        let already_reported =
+            REPORTED[id - 1].fetch_or(1 << (held_id - 1), Ordering::Relaxed) & 1 << (held_id - 1);
+        if already_reported == 0 {
+            report(format_args!(
+                "lock order inversion: lock {} at {} while holding lock {} locked at {}, \
+                 but they've been locked the other way around before\n",
+                id,
+                location,
+                held_id,
+                DisplayLocation(held.location()),
+            ));
+        }
+    }
+}
+
+/// The locks a single CPU is holding, in the order they were locked.
+///
+/// Only ever touched by its own CPU with interrupts disabled, so the atomics
+/// are only there to make the table a `static`.
+struct HeldLocks {
+    len: AtomicUsize,
+    locks: [AtomicPtr<LockInfo>; MAX_HELD],
+}
+
+impl HeldLocks {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<LockInfo> = AtomicPtr::new(ptr::null_mut());
+
+    const fn new() -> Self {
+        HeldLocks {
+            len: AtomicUsize::new(0),
+            locks: [Self::NONE; MAX_HELD],
+        }
+    }
+
+    /// Iterate over the held locks. They're alive as long as they're held.
+    fn iter(&self) -> impl Iterator<Item = &LockInfo> {
+        let len = self.len.load(Ordering::Relaxed);
+
+        self.locks[..len]
+            .iter()
+            .map(|lock| unsafe { &*lock.load(Ordering::Relaxed) })
+    }
+
+    fn push(&self, lock: &LockInfo) {
+        let len = self.len.load(Ordering::Relaxed);
+
+        // Locks beyond `MAX_HELD` just don't get checked against.
+        if let Some(slot) = self.locks.get(len) {
+            slot.store(lock as *const _ as *mut _, Ordering::Relaxed);
+            self.len.store(len + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&self, lock: &LockInfo) {
+        let len = self.len.load(Ordering::Relaxed);
+        let lock = lock as *const _ as *mut _;
+
+        // Locks are usually unlocked in reverse order, so start at the top.
+        let index = (0..len)
+            .rev()
+            .find(|&i| self.locks[i].load(Ordering::Relaxed) == lock);
+
+        if let Some(index) = index {
+            for i in index..len - 1 {
+                let next = self.locks[i + 1].load(Ordering::Relaxed);
+                self.locks[i].store(next, Ordering::Relaxed);
+            }
+            self.len.store(len - 1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The error of locking a lock that the current CPU already holds.
+pub(super) struct Reentrant {
+    cpu: usize,
+    first: Option<&'static Location<'static>>,
+    second: &'static Location<'static>,
+}
+
+impl fmt::Display for Reentrant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "deadlock: CPU {} locked a lock at {} that it already holds since {} \
+             (an interrupt handler, maybe?)",
+            self.cpu,
+            self.second,
+            DisplayLocation(self.first),
+        )
+    }
+}
+
+/// Displays an optional `Location`.
+struct DisplayLocation(Option<&'static Location<'static>>);
+
+impl fmt::Display for DisplayLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(location) => write!(f, "{}", location),
+            None => write!(f, "<unknown>"),
+        }
+    }
+}
@@ -13,8 +13,8 @@ use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use rust_os::{
-    println,
-    task::{executor::Executor, keyboard, Task},
+    println, shell,
+    task::{executor::Executor, keyboard, Builder, Priority},
 };
 
 async fn async_number() -> u32 {
@@ -34,6 +34,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use rust_os::{
         allocator,
         memory::{self, BootInfoFrameAllocator},
+        smp, vga_buffer,
     };
     use x86_64::VirtAddr;
 
@@ -46,6 +47,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    vga_buffer::WRITER.lock().enable_scrollback();
+
+    match unsafe { smp::init(phys_mem_offset, &mut mapper, &mut frame_allocator) } {
+        Ok(cpus) => println!("{} CPUs online", cpus),
+        Err(error) => println!("couldn't start the other CPUs: {}", error),
+    }
 
     // Allocate a number on the heap
     let x = Box::new(41);
@@ -82,8 +89,17 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("It did not crash!");
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(example_task());
+    Builder::new()
+        .name("keyboard")
+        .priority(Priority::Interrupt)
+        .spawn_on(&executor.spawner(), keyboard::run());
+    Builder::new()
+        .name("shell")
+        .spawn_on(&executor.spawner(), shell::run_console());
+    Builder::new()
+        .name("serial_shell")
+        .spawn_on(&executor.spawner(), shell::run_serial());
     executor.run();
 }
 
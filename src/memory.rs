@@ -1,9 +1,26 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+/// The end of the memory below 1 MiB, the only memory that real mode code
+/// like the AP trampoline can run in.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Where the complete physical memory is mapped, as passed to `init`.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// The number of usable frames in the memory map of the
+/// `BootInfoFrameAllocator`.
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+/// The number of frames the `BootInfoFrameAllocator` has handed out.
+static ALLOCATED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
 /// Initialize a new `OffsetPageTable`.
 //,/
 /// # Safety
@@ -12,6 +29,7 @@ use x86_64::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behaviour).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let _ = PHYSICAL_MEMORY_OFFSET.try_init_once(|| physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -36,6 +54,86 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// The number of usable frames in the bootloader's memory map and how many of
+/// them the `BootInfoFrameAllocator` has handed out.
+pub fn frame_usage() -> (usize, usize) {
+    (
+        USABLE_FRAMES.load(Ordering::Relaxed),
+        ALLOCATED_FRAMES.load(Ordering::Relaxed),
+    )
+}
+
+/// One level of a page table walk.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    /// The level of the page table, 4 for the top one.
+    pub level: u8,
+    /// The index of the entry in the table.
+    pub index: u16,
+    /// The physical address of the table.
+    pub table: PhysAddr,
+    pub flags: PageTableFlags,
+    /// The address the entry points to.
+    pub address: PhysAddr,
+}
+
+/// The page table entries that map `addr`, from the top level down, and the
+/// physical address it's mapped to, if it is.
+#[derive(Debug, Clone)]
+pub struct PageWalk {
+    pub steps: [Option<WalkStep>; 4],
+    pub physical: Option<PhysAddr>,
+}
+
+/// Walk the active page tables for `addr`.
+///
+/// Returns `None` if `init` hasn't been called.
+pub fn walk(addr: VirtAddr) -> Option<PageWalk> {
+    use x86_64::registers::control::Cr3;
+
+    let offset = *PHYSICAL_MEMORY_OFFSET.try_get().ok()?;
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut walk = PageWalk {
+        steps: [None; 4],
+        physical: None,
+    };
+
+    let mut table = Cr3::read().0.start_address();
+    for (step, &index) in indexes.iter().enumerate() {
+        let level = 4 - step as u8;
+        let table_ptr: *const PageTable = (offset + table.as_u64()).as_ptr();
+        // The complete physical memory is mapped at `offset`, and page
+        // tables are never freed.
+        let entry = unsafe { &(*table_ptr)[index] };
+
+        walk.steps[step] = Some(WalkStep {
+            level,
+            index: u16::from(index),
+            table,
+            flags: entry.flags(),
+            address: entry.addr(),
+        });
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Some(walk);
+        }
+
+        // Huge pages are 2MiB in level 2 and 1GiB in level 3.
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 4096u64 << (9 * (level - 1));
+            walk.physical = Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            return Some(walk);
+        }
+        table = entry.addr();
+    }
+
+    Some(walk)
+}
+
 /// A `FrameAllocator` that always returns `None`.
 pub struct EmptyFrameAllocator;
 
@@ -51,6 +149,12 @@ pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     /// Keeps track of the next frame that the allocator should return.
     next: usize,
+    /// A usable frame below 1 MiB that is kept back for `allocate_low_frame`.
+    /// It's never part of `usable_frames`, even once it's handed out, so that
+    /// `next` keeps counting the same frames.
+    low_frame: Option<PhysFrame>,
+    /// Set once `allocate_low_frame` has handed out `low_frame`.
+    low_frame_taken: bool,
 }
 
 impl BootInfoFrameAllocator {
@@ -61,14 +165,41 @@ impl BootInfoFrameAllocator {
     /// passed memory map is valid. The main requirement is that all frames that
     /// are marked as `USABLE` in it really are unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            low_frame: None,
+            low_frame_taken: false,
+        };
+        USABLE_FRAMES.store(allocator.usable_frames().count(), Ordering::Relaxed);
+        // The highest one, so that it isn't the null frame.
+        allocator.low_frame = allocator
+            .usable_frames()
+            .filter(|frame| frame.start_address().as_u64() < LOW_MEMORY_END)
+            .max();
+        allocator
+    }
+
+    /// Returns the usable frame below 1 MiB that the allocator keeps back,
+    /// or `None` if there isn't one or it has already been handed out.
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        if self.low_frame_taken {
+            return None;
+        }
+
+        let frame = self.low_frame;
+        if frame.is_some() {
+            self.low_frame_taken = true;
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
         }
+        frame
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
+    /// Returns an iterator over the usable frames specified in the memory map,
+    /// without the one kept back for `allocate_low_frame`.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let low_frame = self.low_frame;
+
         // Get usable regions from memory map:
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
@@ -80,7 +211,9 @@ impl BootInfoFrameAllocator {
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
 
         // Create `PhysFrame` types from the start addresses:
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        frame_addresses
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .filter(move |&frame| Some(frame) != low_frame)
     }
 }
 
@@ -88,6 +221,9 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if frame.is_some() {
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
         frame
     }
 }
@@ -0,0 +1,188 @@
+//! Data that every CPU has its own copy of.
+//!
+//! Each CPU's `PerCpu` is reached through its GS segment base, whose first
+//! word points back at the structure, so `mov reg, gs:[0]` gets its address.
+//! The kernel runs with `IA32_GS_BASE` pointing at the per-CPU data and
+//! `IA32_KERNEL_GS_BASE` zeroed, which is the layout `swapgs` expects once
+//! there's user mode code to switch from.
+//!
+//! Until the bootstrap processor has installed its data in `init_bsp`, only
+//! the BSP is running, so `current` hands out the BSP's data directly.
+
+use crate::task::{Spawner, TaskHeader};
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::tss::TaskStateSegment,
+    VirtAddr,
+};
+
+/// The bootstrap processor's data. It's static so that it can be used before
+/// the heap exists.
+static BSP: PerCpu = PerCpu::new(0);
+
+/// Set once the BSP's GS base points at `BSP`.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The data of a single CPU.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points at this structure once it's installed. Must be the first field.
+    this: AtomicPtr<PerCpu>,
+    id: usize,
+    apic_id: AtomicU8,
+    tss: OnceCell<&'static TaskStateSegment>,
+    /// How many hardware interrupt handlers are running on this CPU.
+    pub(crate) interrupt_depth: AtomicUsize,
+    /// The header of the task the running thread is polling, or null. The
+    /// scheduler keeps a copy for each thread that is switched out.
+    pub(crate) current_task: AtomicPtr<TaskHeader>,
+    /// The ID of the kernel thread running on this CPU, or `NO_THREAD`.
+    pub(crate) current_thread: AtomicU64,
+    /// The spawner of the executor running on this CPU, if any.
+    pub(crate) spawner: OnceCell<Spawner>,
+    stats: CpuStats,
+}
+
+impl PerCpu {
+    /// The value of `current_thread` while no kernel thread is running.
+    pub(crate) const NO_THREAD: u64 = u64::MAX;
+
+    const fn new(id: usize) -> Self {
+        PerCpu {
+            this: AtomicPtr::new(ptr::null_mut()),
+            id,
+            apic_id: AtomicU8::new(0),
+            tss: OnceCell::uninit(),
+            interrupt_depth: AtomicUsize::new(0),
+            current_task: AtomicPtr::new(ptr::null_mut()),
+            current_thread: AtomicU64::new(Self::NO_THREAD),
+            spawner: OnceCell::uninit(),
+            stats: CpuStats::new(),
+        }
+    }
+
+    /// The CPU's index. The BSP is CPU 0, and the APs are numbered in the
+    /// order they were started.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The ID of the CPU's local APIC.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// The CPU's Task State Segment, once it has loaded one.
+    pub fn tss(&self) -> Option<&'static TaskStateSegment> {
+        self.tss.try_get().ok().copied()
+    }
+
+    /// Returns `true` if this is the bootstrap processor.
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+
+    /// Statistics about the work this CPU has done.
+    pub fn stats(&self) -> &CpuStats {
+        &self.stats
+    }
+
+    /// Point the current CPU's GS base at this structure.
+    fn install(&'static self, tss: &'static TaskStateSegment) {
+        // Bits 24..32 of EBX hold the initial APIC ID.
+        let apic_id = unsafe { __cpuid(1) }.ebx >> 24;
+        self.apic_id.store(apic_id as u8, Ordering::Relaxed);
+        self.tss.init_once(|| tss);
+        self.this
+            .store(self as *const _ as *mut _, Ordering::Release);
+
+        let address = VirtAddr::from_ptr(self);
+        GsBase::write(address);
+        KernelGsBase::write(VirtAddr::new(0));
+    }
+}
+
+/// Counters of the work a CPU has done.
+#[derive(Debug)]
+pub struct CpuStats {
+    interrupts: AtomicU64,
+    context_switches: AtomicU64,
+    task_polls: AtomicU64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        CpuStats {
+            interrupts: AtomicU64::new(0),
+            context_switches: AtomicU64::new(0),
+            task_polls: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of hardware interrupts handled.
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// The number of switches between kernel threads.
+    pub fn context_switches(&self) -> u64 {
+        self.context_switches.load(Ordering::Relaxed)
+    }
+
+    /// The number of times an async task was polled.
+    pub fn task_polls(&self) -> u64 {
+        self.task_polls.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_context_switch(&self) {
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_task_poll(&self) {
+        self.task_polls.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Get the data of the CPU we're running on.
+///
+/// The reference stays valid forever, but it's only meaningful while we stay
+/// on the same CPU. Nothing migrates between CPUs yet.
+pub fn current() -> &'static PerCpu {
+    if !INSTALLED.load(Ordering::Acquire) {
+        return &BSP;
+    }
+
+    let this: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) this,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*this
+    }
+}
+
+/// Install the BSP's per-CPU data. Called by `init`, once the GDT is loaded.
+pub(crate) fn init_bsp(tss: &'static TaskStateSegment) {
+    BSP.install(tss);
+    INSTALLED.store(true, Ordering::Release);
+}
+
+/// Allocate and install the per-CPU data of an AP. Must be called by the AP
+/// before anything else uses `current`.
+pub(crate) fn init_ap(id: usize, tss: &'static TaskStateSegment) {
+    let data: &'static PerCpu = Box::leak(Box::new(PerCpu::new(id)));
+    data.install(tss);
+}
@@ -0,0 +1,41 @@
+//! Rebooting and turning off the machine.
+
+use crate::{hlt_loop, ps2};
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The reset control register of the chipset.
+const RESET_CONTROL: u16 = 0xcf9;
+/// Do a full reset rather than just resetting the CPU.
+const FULL_RESET: u8 = 0x06;
+
+/// The ACPI power management control ports of some emulators and the value
+/// that turns them off: QEMU, Bochs and older QEMU versions, and VirtualBox.
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] =
+    [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+
+/// Restart the machine.
+///
+/// This first tries the PS/2 controller's reset line and then the chipset's
+/// reset control register. If neither works, it halts.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    ps2::pulse_reset_line();
+    unsafe { Port::<u8>::new(RESET_CONTROL).write(FULL_RESET) };
+
+    hlt_loop();
+}
+
+/// Turn off the machine.
+///
+/// Real hardware would need the sleep state values from the ACPI DSDT, so
+/// this only works in QEMU, Bochs and VirtualBox. Elsewhere, it halts.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    for &(port, value) in EMULATOR_SHUTDOWN_PORTS.iter() {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+
+    hlt_loop();
+}
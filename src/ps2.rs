@@ -0,0 +1,567 @@
+//! The 8042 PS/2 controller that the keyboard and mouse are attached to.
+//!
+//! `init` puts the controller into a known state instead of relying on what
+//! the firmware left behind. It disables both ports, flushes stale bytes, runs
+//! the controller and port self-tests, resets and identifies the attached
+//! devices and finally turns on the interrupts of every working port.
+//!
+//! Ports without a device stay enabled, so a keyboard that is plugged in
+//! later still gets its input through. `reset_device` probes a port again.
+//!
+//! Everything here polls the controller with the port interrupts masked in
+//! the controller, since the replies would otherwise end up in the interrupt
+//! handlers. Bytes that are sent with `send` are answered through the
+//! interrupt handlers instead.
+
+use crate::lock::IrqSafeMutex;
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+/// The data port, shared by the controller and both devices.
+const DATA_PORT: u16 = 0x60;
+/// Reads return the status register, writes are controller commands.
+const STATUS_PORT: u16 = 0x64;
+
+// Status register bits.
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const TEST_CONTROLLER: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+/// Sends the next data byte to the second port's device.
+const WRITE_SECOND_PORT: u8 = 0xd4;
+/// Pulse the output line that resets the CPU.
+const PULSE_RESET_LINE: u8 = 0xfe;
+
+// Configuration byte bits.
+const FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+const SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+const SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+/// Translate the first port's scancodes from set 2 to set 1.
+const TRANSLATION: u8 = 1 << 6;
+
+/// The controller's reply to a successful self-test.
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+/// The reply to a successful port test.
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Commands understood by both keyboards and mice.
+const IDENTIFY: u8 = 0xf2;
+const ENABLE_SCANNING: u8 = 0xf4;
+const DISABLE_SCANNING: u8 = 0xf5;
+const RESET: u8 = 0xff;
+
+/// Sent by a device once it has accepted a command or data byte.
+pub(crate) const ACK: u8 = 0xfa;
+/// Sent by a device if it wants the last byte again.
+pub(crate) const RESEND: u8 = 0xfe;
+/// Sent by a device after it passed its self-test.
+const SELF_TEST_PASSED: u8 = 0xaa;
+
+/// How many times a device command is sent before giving up on `RESEND`s.
+const RETRIES: usize = 3;
+/// How often the status register is polled before giving up. Reading it
+/// takes about a microsecond, so this is roughly 100ms.
+const TIMEOUT: usize = 100_000;
+/// How often the status register is polled while a device runs its
+/// self-test after a reset, which can take a lot longer.
+const RESET_TIMEOUT: usize = 10 * TIMEOUT;
+
+static CONTROLLER: IrqSafeMutex<Controller> = IrqSafeMutex::new(Controller::new());
+
+/// Errors that can occur while talking to the controller or a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// Nothing answers at the controller's I/O ports.
+    NoController,
+    /// The controller failed its self-test, replying with this byte.
+    SelfTestFailed(u8),
+    /// The controller or device didn't accept or answer a byte in time.
+    Timeout,
+    /// A device replied with this byte instead of acknowledging a command.
+    UnexpectedReply(u8),
+    /// The port is missing or failed its self-test.
+    NoPort,
+    /// The port has this device instead of the one that was expected.
+    WrongDevice(DeviceType),
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ps2Error::NoController => write!(f, "no PS/2 controller found"),
+            Ps2Error::SelfTestFailed(reply) => {
+                write!(f, "PS/2 controller self-test failed ({:#x})", reply)
+            }
+            Ps2Error::Timeout => write!(f, "PS/2 controller timed out"),
+            Ps2Error::UnexpectedReply(reply) => {
+                write!(f, "unexpected reply {:#x} from PS/2 device", reply)
+            }
+            Ps2Error::NoPort => write!(f, "PS/2 port missing or broken"),
+            Ps2Error::WrongDevice(device) => write!(f, "unexpected PS/2 device {:?}", device),
+        }
+    }
+}
+
+/// One of the controller's two ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    /// The port the keyboard is usually attached to, on IRQ 1.
+    First,
+    /// The auxiliary port the mouse is usually attached to, on IRQ 12.
+    Second,
+}
+
+impl Ps2Port {
+    /// Both ports.
+    pub const ALL: [Ps2Port; 2] = [Ps2Port::First, Ps2Port::Second];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn test_command(self) -> u8 {
+        match self {
+            Ps2Port::First => TEST_FIRST_PORT,
+            Ps2Port::Second => TEST_SECOND_PORT,
+        }
+    }
+
+    fn enable_command(self) -> u8 {
+        match self {
+            Ps2Port::First => ENABLE_FIRST_PORT,
+            Ps2Port::Second => ENABLE_SECOND_PORT,
+        }
+    }
+
+    fn interrupt_bit(self) -> u8 {
+        match self {
+            Ps2Port::First => FIRST_PORT_INTERRUPT,
+            Ps2Port::Second => SECOND_PORT_INTERRUPT,
+        }
+    }
+}
+
+/// The kind of device attached to a port, from its reply to `IDENTIFY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    /// An old AT keyboard, which doesn't send an ID.
+    AtKeyboard,
+    /// An MF2 keyboard, which is what every keyboard since then is.
+    Mf2Keyboard,
+    /// A mouse with three buttons.
+    Mouse,
+    /// A mouse with a scroll wheel.
+    ScrollMouse,
+    /// A mouse with a scroll wheel and five buttons.
+    FiveButtonMouse,
+    /// Something else, with its ID bytes.
+    Unknown([u8; 2]),
+}
+
+impl DeviceType {
+    fn from_id(id: &[u8]) -> Self {
+        match id {
+            [] => DeviceType::AtKeyboard,
+            [0x00] => DeviceType::Mouse,
+            [0x03] => DeviceType::ScrollMouse,
+            [0x04] => DeviceType::FiveButtonMouse,
+            [0xab, 0x41] | [0xab, 0x83] | [0xab, 0xc1] => DeviceType::Mf2Keyboard,
+            [first] => DeviceType::Unknown([*first, 0]),
+            [first, second, ..] => DeviceType::Unknown([*first, *second]),
+        }
+    }
+
+    /// Returns `true` for keyboards.
+    pub fn is_keyboard(self) -> bool {
+        matches!(self, DeviceType::AtKeyboard | DeviceType::Mf2Keyboard)
+    }
+
+    /// Returns `true` for mice.
+    pub fn is_mouse(self) -> bool {
+        matches!(
+            self,
+            DeviceType::Mouse | DeviceType::ScrollMouse | DeviceType::FiveButtonMouse
+        )
+    }
+}
+
+/// What was found at a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortStatus {
+    /// The controller wasn't initialized, or doesn't have this port.
+    Missing,
+    /// The port failed its self-test, with the controller's reply.
+    Failed(u8),
+    /// The port works, but no device answered.
+    Empty,
+    /// A working device is attached.
+    Device(DeviceType),
+}
+
+impl PortStatus {
+    /// Returns `true` if a keyboard is attached.
+    pub fn is_keyboard(self) -> bool {
+        matches!(self, PortStatus::Device(device) if device.is_keyboard())
+    }
+
+    /// Returns `true` if a mouse is attached.
+    pub fn is_mouse(self) -> bool {
+        matches!(self, PortStatus::Device(device) if device.is_mouse())
+    }
+
+    /// Returns `true` if the port is enabled.
+    fn is_usable(self) -> bool {
+        matches!(self, PortStatus::Empty | PortStatus::Device(_))
+    }
+}
+
+impl fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortStatus::Missing => write!(f, "missing"),
+            PortStatus::Failed(reply) => write!(f, "failed self-test ({:#x})", reply),
+            PortStatus::Empty => write!(f, "no device"),
+            PortStatus::Device(device) => write!(f, "{:?}", device),
+        }
+    }
+}
+
+/// Initialize the controller and the devices attached to it.
+///
+/// Must be called with interrupts disabled, before the keyboard and mouse
+/// interrupts are needed.
+pub fn init() -> Result<(), Ps2Error> {
+    let mut controller = CONTROLLER.lock();
+    let result = controller.init();
+    if result.is_err() {
+        controller.ports = [PortStatus::Missing; 2];
+    }
+    result
+}
+
+/// What was found at `port` during `init` or the last `reset_device`.
+pub fn port_status(port: Ps2Port) -> PortStatus {
+    CONTROLLER.lock().ports[port.index()]
+}
+
+/// Reset the device at `port` and identify it again, for example after it
+/// was plugged in.
+///
+/// This polls the controller with interrupts disabled, which can take a
+/// second if nothing answers.
+pub fn reset_device(port: Ps2Port) -> PortStatus {
+    CONTROLLER.lock().reprobe(port)
+}
+
+/// Send `bytes` to the device at `port` one after the other, waiting for
+/// each of them to be acknowledged.
+pub(crate) fn command(port: Ps2Port, bytes: &[u8]) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().with_masked(port, |controller| {
+        bytes
+            .iter()
+            .try_for_each(|&byte| controller.command_device(port, byte))
+    })
+}
+
+/// Ask the device at `port` what it is, without resetting it. This leaves
+/// keyboard scanning and mouse data reporting disabled.
+pub(crate) fn identify(port: Ps2Port) -> Result<DeviceType, Ps2Error> {
+    let mut controller = CONTROLLER.lock();
+    let device = controller.with_masked(port, |controller| controller.read_id(port))?;
+    controller.ports[port.index()] = PortStatus::Device(device);
+    Ok(device)
+}
+
+/// Turn the controller's translation of the first port's scancodes from set
+/// 2 to set 1 on or off. It's on after `init`.
+pub(crate) fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().with_masked(Ps2Port::First, |controller| {
+        let config = controller.read_config()?;
+        if enabled {
+            controller.write_config(config | TRANSLATION)
+        } else {
+            controller.write_config(config & !TRANSLATION)
+        }
+    })
+}
+
+/// Reset the CPU through the controller's reset line. This returns if the
+/// controller doesn't do it.
+pub(crate) fn pulse_reset_line() {
+    let _ = CONTROLLER.lock().command(PULSE_RESET_LINE);
+}
+
+/// Send `byte` to the device at `port` without waiting for a reply. Replies
+/// arrive through the port's interrupt handler.
+pub(crate) fn send(port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().write_device(port, byte)
+}
+
+struct Controller {
+    data: Port<u8>,
+    status: Port<u8>,
+    ports: [PortStatus; 2],
+}
+
+impl Controller {
+    const fn new() -> Self {
+        Controller {
+            data: Port::new(DATA_PORT),
+            status: Port::new(STATUS_PORT),
+            ports: [PortStatus::Missing; 2],
+        }
+    }
+
+    fn init(&mut self) -> Result<(), Ps2Error> {
+        // Nobody drives the bus without a controller, so it reads all ones.
+        if self.read_status() == 0xff {
+            return Err(Ps2Error::NoController);
+        }
+
+        self.command(DISABLE_FIRST_PORT)?;
+        self.command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        let mut config = self.read_config()?;
+        config &= !(FIRST_PORT_INTERRUPT | SECOND_PORT_INTERRUPT);
+        // Start out with set 1 scancodes, whatever the firmware chose.
+        config |= TRANSLATION;
+        // The second port's clock was just disabled, so if it isn't, there
+        // is no second port.
+        let maybe_dual = config & SECOND_PORT_CLOCK_DISABLED != 0;
+        self.write_config(config)?;
+
+        self.command(TEST_CONTROLLER)?;
+        match self.read(TIMEOUT)? {
+            CONTROLLER_TEST_PASSED => {}
+            reply => return Err(Ps2Error::SelfTestFailed(reply)),
+        }
+        // Some controllers reset themselves during the self-test.
+        self.write_config(config)?;
+
+        let dual = maybe_dual && {
+            self.command(ENABLE_SECOND_PORT)?;
+            let enabled = self.read_config()? & SECOND_PORT_CLOCK_DISABLED == 0;
+            self.command(DISABLE_SECOND_PORT)?;
+            enabled
+        };
+
+        for &port in Ps2Port::ALL.iter() {
+            if port == Ps2Port::Second && !dual {
+                continue;
+            }
+
+            self.command(port.test_command())?;
+            self.ports[port.index()] = match self.read(TIMEOUT)? {
+                PORT_TEST_PASSED => PortStatus::Empty,
+                reply => PortStatus::Failed(reply),
+            };
+        }
+
+        for &port in Ps2Port::ALL.iter() {
+            if self.ports[port.index()].is_usable() {
+                self.command(port.enable_command())?;
+                self.ports[port.index()] = self.probe(port);
+            }
+        }
+
+        // Enabling the ports changed the clock bits, so read it again.
+        let mut config = self.read_config()?;
+        for &port in Ps2Port::ALL.iter() {
+            if self.ports[port.index()].is_usable() {
+                config |= port.interrupt_bit();
+            }
+        }
+        self.write_config(config)
+    }
+
+    /// Probe `port` again with its interrupt masked.
+    fn reprobe(&mut self, port: Ps2Port) -> PortStatus {
+        if !self.ports[port.index()].is_usable() {
+            return self.ports[port.index()];
+        }
+
+        let status = self
+            .with_masked(port, |controller| Ok(controller.probe(port)))
+            .unwrap_or(PortStatus::Empty);
+        self.ports[port.index()] = status;
+        status
+    }
+
+    /// Run `f` with the interrupt of `port` masked, so that the replies to
+    /// polled commands don't end up in the interrupt handler.
+    fn with_masked<T>(
+        &mut self,
+        port: Ps2Port,
+        f: impl FnOnce(&mut Self) -> Result<T, Ps2Error>,
+    ) -> Result<T, Ps2Error> {
+        if !self.ports[port.index()].is_usable() {
+            return Err(Ps2Error::NoPort);
+        }
+
+        let config = self.read_config()?;
+        self.write_config(config & !port.interrupt_bit())?;
+        let result = f(self);
+        // Only the interrupt bit is restored, so that `f` can change the rest
+        // of the configuration. If this fails too, the controller is gone and
+        // there's nothing to restore.
+        let interrupt = config & port.interrupt_bit();
+        let _ = self
+            .read_config()
+            .and_then(|current| self.write_config(current | interrupt));
+        result
+    }
+
+    /// Reset and identify the device at `port`. Keyboards are left scanning,
+    /// mice with data reporting disabled.
+    fn probe(&mut self, port: Ps2Port) -> PortStatus {
+        match self.identify(port) {
+            Ok(device) => PortStatus::Device(device),
+            Err(_) => PortStatus::Empty,
+        }
+    }
+
+    fn identify(&mut self, port: Ps2Port) -> Result<DeviceType, Ps2Error> {
+        self.command_device(port, RESET)?;
+        match self.read(RESET_TIMEOUT)? {
+            SELF_TEST_PASSED => {}
+            reply => return Err(Ps2Error::UnexpectedReply(reply)),
+        }
+        // Mice send their ID right after the self-test result.
+        let _ = self.read(TIMEOUT / 10);
+
+        let device = self.read_id(port)?;
+        if device.is_keyboard() {
+            self.command_device(port, ENABLE_SCANNING)?;
+        }
+        Ok(device)
+    }
+
+    /// Disable scanning on the device at `port` and get its ID.
+    fn read_id(&mut self, port: Ps2Port) -> Result<DeviceType, Ps2Error> {
+        self.command_device(port, DISABLE_SCANNING)?;
+        self.command_device(port, IDENTIFY)?;
+        let mut id = [0; 2];
+        let mut len = 0;
+        while len < id.len() {
+            match self.read(TIMEOUT) {
+                Ok(byte) => id[len] = byte,
+                Err(_) => break,
+            }
+            len += 1;
+        }
+
+        Ok(DeviceType::from_id(&id[..len]))
+    }
+
+    /// Send `byte` to the device at `port` and wait for it to be
+    /// acknowledged.
+    fn command_device(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RETRIES {
+            self.write_device(port, byte)?;
+            match self.read(TIMEOUT)? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                reply => return Err(Ps2Error::UnexpectedReply(reply)),
+            }
+        }
+        Err(Ps2Error::UnexpectedReply(RESEND))
+    }
+
+    fn write_device(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+        if port == Ps2Port::Second {
+            self.command(WRITE_SECOND_PORT)?;
+        }
+        self.write(byte)
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command(READ_CONFIG)?;
+        self.read(TIMEOUT)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write(config)
+    }
+
+    fn read_status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    /// Send a command to the controller itself.
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_for_input_space()?;
+        unsafe { self.status.write(command) };
+        Ok(())
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait_for_input_space()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    /// Wait up to `timeout` status polls for a byte and read it.
+    fn read(&mut self, timeout: usize) -> Result<u8, Ps2Error> {
+        for _ in 0..timeout {
+            if self.read_status() & OUTPUT_FULL != 0 {
+                return Ok(unsafe { self.data.read() });
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn wait_for_input_space(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT {
+            if self.read_status() & INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Throw away any bytes that are waiting to be read.
+    fn flush(&mut self) {
+        // Don't loop forever on a broken controller that always has data.
+        for _ in 0..64 {
+            if self.read_status() & OUTPUT_FULL == 0 {
+                break;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn device_types() {
+        assert_eq!(DeviceType::from_id(&[]), DeviceType::AtKeyboard);
+        assert_eq!(DeviceType::from_id(&[0xab, 0x83]), DeviceType::Mf2Keyboard);
+        assert_eq!(DeviceType::from_id(&[0x03]), DeviceType::ScrollMouse);
+        assert_eq!(
+            DeviceType::from_id(&[0x12, 0x34]),
+            DeviceType::Unknown([0x12, 0x34])
+        );
+        assert!(DeviceType::Mf2Keyboard.is_keyboard());
+        assert!(DeviceType::FiveButtonMouse.is_mouse());
+    }
+
+    #[test_case]
+    fn qemu_keyboard_is_found() {
+        // `init` runs before the tests, and QEMU always has a keyboard.
+        assert!(port_status(Ps2Port::First).is_keyboard());
+    }
+}
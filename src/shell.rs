@@ -0,0 +1,175 @@
+//! A small interactive shell.
+//!
+//! `run_console` reads commands from the keyboard with line editing and runs
+//! them with their output on the VGA text buffer. `run_serial` does the same
+//! over the first serial port.
+//!
+//! Besides the built-in commands, other modules can add their own with
+//! `register`.
+
+mod commands;
+
+use crate::{
+    lock::IrqSafeMutex,
+    serial::SERIAL1,
+    serial_print,
+    task::{readline::Readline, serial::SerialInput},
+    vga_buffer::WRITER,
+};
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+const PROMPT: &str = "> ";
+
+/// The commands added with `register`.
+static COMMANDS: IrqSafeMutex<Vec<Command>> = IrqSafeMutex::new(Vec::new());
+
+/// The function that runs a command, with the arguments after the command's
+/// name.
+pub type Handler = fn(output: &mut Output, args: &[&str]) -> fmt::Result;
+
+/// A command the shell can run.
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// The arguments the command takes, like `<addr>`, for `help`.
+    pub usage: &'static str,
+    /// A short description for `help`.
+    pub description: &'static str,
+    pub run: Handler,
+}
+
+/// Add `command` to the shell. It replaces any command with the same name,
+/// including the built-in ones.
+pub fn register(command: Command) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|registered| registered.name != command.name);
+    commands.push(command);
+}
+
+/// Every command, sorted by name.
+pub fn commands() -> Vec<Command> {
+    let mut all = COMMANDS.lock().clone();
+    for builtin in commands::BUILTINS.iter() {
+        if !all.iter().any(|command| command.name == builtin.name) {
+            all.push(*builtin);
+        }
+    }
+    all.sort_by_key(|command| command.name);
+    all
+}
+
+/// Look up the command called `name`.
+fn find(name: &str) -> Option<Command> {
+    let registered = COMMANDS
+        .lock()
+        .iter()
+        .find(|command| command.name == name)
+        .copied();
+    registered.or_else(|| {
+        commands::BUILTINS
+            .iter()
+            .find(|command| command.name == name)
+            .copied()
+    })
+}
+
+/// The console a shell runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    /// The keyboard and the VGA text buffer.
+    Vga,
+    /// The first serial port.
+    Serial,
+}
+
+/// Where a command's output goes. Write to it with `write!` and `writeln!`.
+pub struct Output {
+    console: Console,
+}
+
+impl Output {
+    pub fn new(console: Console) -> Self {
+        Output { console }
+    }
+
+    pub fn console(&self) -> Console {
+        self.console
+    }
+}
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.console {
+            Console::Vga => WRITER.lock().write_str(s),
+            Console::Serial => SERIAL1.lock().write_str(s),
+        }
+    }
+}
+
+/// Run the command in `line`, with its output going to `output`.
+pub fn execute(output: &mut Output, line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return,
+    };
+
+    let result = match find(name) {
+        Some(command) => (command.run)(output, args),
+        None => writeln!(output, "{}: command not found; try `help`", name),
+    };
+    // Neither console can fail to take the output, but commands can give up
+    // on their own.
+    if result.is_err() {
+        let _ = writeln!(output, "{}: failed", name);
+    }
+}
+
+/// The completions for the last word of `line`: command names for the first
+/// word and the argument of `help`.
+fn complete(line: &str) -> Vec<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let at_new_word = line.ends_with(' ') || line.is_empty();
+    let completes_name = match (words.len(), at_new_word) {
+        (0, _) | (1, false) => true,
+        (1, true) | (2, false) => words[0] == "help",
+        _ => false,
+    };
+
+    if !completes_name {
+        return Vec::new();
+    }
+    commands()
+        .iter()
+        .map(|command| String::from(command.name))
+        .collect()
+}
+
+/// The shell on the VGA console.
+pub async fn run_console() {
+    let mut readline = Readline::new();
+    readline.set_completer(complete);
+    let mut output = Output::new(Console::Vga);
+
+    while let Some(line) = readline.read_line(PROMPT).await {
+        execute(&mut output, &line);
+    }
+}
+
+/// The shell on the first serial port.
+///
+/// # Panics
+/// Panics if a `SerialInput` was created before.
+pub async fn run_serial() {
+    let mut input = SerialInput::new();
+    let mut output = Output::new(Console::Serial);
+
+    loop {
+        serial_print!("{}", PROMPT);
+        match input.read_line().await {
+            Some(line) => execute(&mut output, &line),
+            None => break,
+        }
+    }
+}
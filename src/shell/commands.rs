@@ -0,0 +1,276 @@
+//! The built-in shell commands.
+
+use super::{Command, Console, Output};
+use crate::{
+    allocator::{self, HEAP_SIZE},
+    interrupts, memory, power,
+    task::{executor, Priority},
+    vga_buffer::{self, WRITER},
+};
+use core::fmt::{self, Write};
+use x86_64::VirtAddr;
+
+/// The timer ticks per second at the PIT's default rate of 1193182 / 65536
+/// Hz, times 1000.
+const TICKS_PER_1000_SECONDS: u64 = 18_206;
+
+pub(super) const BUILTINS: [Command; 10] = [
+    Command {
+        name: "help",
+        usage: "[command]",
+        description: "list the commands, or describe one",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        description: "show how much of the heap and of the physical frames is used",
+        run: mem,
+    },
+    Command {
+        name: "tasks",
+        usage: "",
+        description: "list the tasks of this CPU's executor",
+        run: tasks,
+    },
+    Command {
+        name: "uptime",
+        usage: "",
+        description: "show how long the kernel has been running",
+        run: uptime,
+    },
+    Command {
+        name: "clear",
+        usage: "",
+        description: "clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "echo",
+        usage: "[text...]",
+        description: "print the arguments",
+        run: echo,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        description: "restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        usage: "",
+        description: "turn off the machine",
+        run: shutdown,
+    },
+    Command {
+        name: "pagewalk",
+        usage: "<addr>",
+        description: "show the page table entries that map a virtual address",
+        run: pagewalk,
+    },
+    Command {
+        name: "dumplog",
+        usage: "",
+        description: "write the VGA console's scrollback to the serial port",
+        run: dumplog,
+    },
+];
+
+fn help(output: &mut Output, args: &[&str]) -> fmt::Result {
+    let commands = super::commands();
+
+    if let Some(&name) = args.first() {
+        return match commands.iter().find(|command| command.name == name) {
+            Some(command) => writeln!(
+                output,
+                "{} {}: {}",
+                command.name, command.usage, command.description
+            ),
+            None => writeln!(output, "help: no command called {}", name),
+        };
+    }
+
+    for command in &commands {
+        writeln!(
+            output,
+            "{:<9} {:<10} {}",
+            command.name, command.usage, command.description
+        )?;
+    }
+    Ok(())
+}
+
+fn mem(output: &mut Output, _args: &[&str]) -> fmt::Result {
+    let heap_used = allocator::heap_used();
+    writeln!(
+        output,
+        "heap:   {} of {} KiB used",
+        (heap_used + 1023) / 1024,
+        HEAP_SIZE / 1024
+    )?;
+
+    let (usable, allocated) = memory::frame_usage();
+    writeln!(
+        output,
+        "frames: {} of {} used, {} KiB free",
+        allocated,
+        usable,
+        (usable - allocated) * 4
+    )
+}
+
+fn tasks(output: &mut Output, _args: &[&str]) -> fmt::Result {
+    let spawner = match executor::spawner() {
+        Some(spawner) => spawner,
+        None => return writeln!(output, "tasks: no executor on this CPU"),
+    };
+
+    writeln!(
+        output,
+        "{:>4} {:<10} {:>8} {:<8} NAME",
+        "ID", "PRIORITY", "POLLS", "STATE"
+    )?;
+    for task in spawner.snapshot() {
+        let state = if task.aborted {
+            "aborted"
+        } else if task.scheduled {
+            "ready"
+        } else {
+            "waiting"
+        };
+        writeln!(
+            output,
+            "{:>4} {:<10} {:>8} {:<8} {}",
+            task.id,
+            priority_name(task.priority),
+            task.polls,
+            state,
+            task.name.as_deref().unwrap_or("-")
+        )?;
+    }
+    Ok(())
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Interrupt => "interrupt",
+        Priority::Normal => "normal",
+        Priority::Background => "background",
+    }
+}
+
+fn uptime(output: &mut Output, _args: &[&str]) -> fmt::Result {
+    let seconds = interrupts::ticks() * 1000 / TICKS_PER_1000_SECONDS;
+    writeln!(
+        output,
+        "up {}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn clear(output: &mut Output, _args: &[&str]) -> fmt::Result {
+    match output.console() {
+        Console::Vga => {
+            WRITER.lock().clear_screen();
+            Ok(())
+        }
+        // Clear the screen and move the cursor to the top left corner.
+        Console::Serial => output.write_str("\x1b[2J\x1b[H"),
+    }
+}
+
+fn echo(output: &mut Output, args: &[&str]) -> fmt::Result {
+    for (index, arg) in args.iter().enumerate() {
+        if index > 0 {
+            output.write_char(' ')?;
+        }
+        output.write_str(arg)?;
+    }
+    writeln!(output)
+}
+
+fn reboot(_output: &mut Output, _args: &[&str]) -> fmt::Result {
+    power::reboot();
+}
+
+fn shutdown(_output: &mut Output, _args: &[&str]) -> fmt::Result {
+    power::shutdown();
+}
+
+fn pagewalk(output: &mut Output, args: &[&str]) -> fmt::Result {
+    let addr = match args {
+        [addr] => parse_address(addr),
+        _ => return writeln!(output, "usage: pagewalk <addr>"),
+    };
+    let addr = match addr.and_then(|addr| VirtAddr::try_new(addr).ok()) {
+        Some(addr) => addr,
+        None => {
+            return writeln!(
+                output,
+                "pagewalk: {} isn't a valid virtual address",
+                args[0]
+            )
+        }
+    };
+    let walk = match memory::walk(addr) {
+        Some(walk) => walk,
+        None => return writeln!(output, "pagewalk: the page tables aren't set up yet"),
+    };
+
+    for step in walk.steps.iter().flatten() {
+        writeln!(
+            output,
+            "P{}[{:>3}] in {:#x}: {:#x} {:?}",
+            step.level,
+            step.index,
+            step.table.as_u64(),
+            step.address.as_u64(),
+            step.flags
+        )?;
+    }
+    match walk.physical {
+        Some(physical) => writeln!(output, "{:#x} -> {:#x}", addr.as_u64(), physical.as_u64()),
+        None => writeln!(output, "{:#x} isn't mapped", addr.as_u64()),
+    }
+}
+
+fn dumplog(output: &mut Output, _args: &[&str]) -> fmt::Result {
+    vga_buffer::dump_scrollback_to_serial();
+    if output.console() == Console::Vga {
+        writeln!(output, "dumplog: written to the serial port")?;
+    }
+    Ok(())
+}
+
+/// Parse a hexadecimal address with a `0x` prefix or a decimal one.
+fn parse_address(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn addresses() {
+        assert_eq!(parse_address("0xb8000"), Some(0xb8000));
+        assert_eq!(parse_address("4096"), Some(4096));
+        assert_eq!(parse_address("0x"), None);
+        assert_eq!(parse_address("b8000"), None);
+    }
+
+    #[test_case]
+    fn builtin_names_are_unique() {
+        for (index, command) in BUILTINS.iter().enumerate() {
+            assert!(BUILTINS[index + 1..]
+                .iter()
+                .all(|other| other.name != command.name));
+        }
+    }
+}
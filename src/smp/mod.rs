@@ -0,0 +1,252 @@
+//! Starting the application processors (APs).
+//!
+//! The bootstrap processor (BSP) finds the other CPUs in the ACPI MADT and
+//! wakes them up one at a time with the INIT-SIPI-SIPI sequence. Each AP
+//! starts out in real mode in the trampoline, switches to long mode on the
+//! BSP's page tables and ends up in `ap_main` on its own stack, where it loads
+//! its own GDT, TSS and per-CPU data and the shared IDT, and then runs an
+//! executor that steals tasks from the other CPUs.
+
+mod trampoline;
+
+use crate::{
+    acpi::{self, AcpiError},
+    apic, gdt, interrupts,
+    memory::BootInfoFrameAllocator,
+    percpu, println,
+    task::executor::Executor,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use trampoline::Trampoline;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// The start of the virtual memory region the AP stacks are mapped in.
+const AP_STACKS_START: u64 = 0x_5555_1000_0000;
+/// The size of an AP's kernel stack in pages.
+const STACK_PAGES: u64 = 4;
+/// The size of an AP's Double Fault stack in pages.
+const DOUBLE_FAULT_STACK_PAGES: u64 = 2;
+/// The pages reserved for each AP: an unmapped guard page below each of its
+/// two stacks, so that overflowing them page faults instead of corrupting
+/// memory.
+const AP_SLOT_PAGES: u64 = 1 + STACK_PAGES + 1 + DOUBLE_FAULT_STACK_PAGES;
+
+/// The number of CPUs that are up and running.
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Set by the AP that is being started once it's running Rust code.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Errors that can occur while starting the APs.
+#[derive(Debug)]
+pub enum SmpError {
+    /// The processors couldn't be found.
+    Acpi(AcpiError),
+    /// There's no free frame below 1 MiB for the trampoline.
+    NoLowMemory,
+    /// Mapping the local APIC, the trampoline or an AP stack failed.
+    Mapping(MapToError<Size4KiB>),
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(error: AcpiError) -> Self {
+        SmpError::Acpi(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        SmpError::Mapping(error)
+    }
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmpError::Acpi(error) => write!(f, "{}", error),
+            SmpError::NoLowMemory => write!(f, "no free frame below 1 MiB for the AP trampoline"),
+            SmpError::Mapping(error) => write!(f, "mapping failed: {:?}", error),
+        }
+    }
+}
+
+/// The number of CPUs that are up and running, including the BSP.
+pub fn cpu_count() -> usize {
+    CPUS_ONLINE.load(Ordering::Acquire)
+}
+
+/// Start every AP listed in the ACPI tables, returning the number of CPUs
+/// that are running afterwards.
+///
+/// If an AP doesn't respond, the ones after it aren't started: it might
+/// still wake up late, and it would run on the stack and with the index that
+/// the trampoline was prepared with for the next one.
+///
+/// The trampoline goes in the frame below 1 MiB that `frame_allocator` keeps
+/// back for it.
+///
+/// # Safety
+/// The complete physical memory must be mapped at `physical_memory_offset`,
+/// and this must only be called once, on the BSP.
+pub unsafe fn init(
+    physical_memory_offset: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<usize, SmpError> {
+    let madt = acpi::parse_madt(physical_memory_offset)?;
+
+    apic::init(madt.local_apic_address, mapper, frame_allocator)?;
+    let local_apic = apic::local_apic().expect("local APIC was just initialized");
+    local_apic.enable();
+    let bsp_id = local_apic.id();
+
+    let frame = frame_allocator
+        .allocate_low_frame()
+        .ok_or(SmpError::NoLowMemory)?;
+    identity_map(frame, mapper, frame_allocator)?;
+    let trampoline = Trampoline::install(frame, physical_memory_offset, ap_main);
+
+    let aps = madt
+        .processors
+        .iter()
+        .filter(|processor| processor.apic_id != bsp_id);
+    for (index, processor) in aps.enumerate() {
+        let index = index as u64;
+        map_stacks(index, mapper, frame_allocator)?;
+
+        if !start_ap(&trampoline, processor.apic_id, index) {
+            println!(
+                "smp: CPU with APIC ID {} didn't come online, not starting any more",
+                processor.apic_id
+            );
+            break;
+        }
+    }
+
+    Ok(cpu_count())
+}
+
+/// Identity map `frame`, so that the trampoline keeps running when it turns
+/// on paging.
+fn identity_map(
+    frame: PhysFrame,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        // The bootloader might have mapped it already.
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(error) => return Err(error),
+    }
+    Ok(())
+}
+
+/// The first page of the `index`th AP's slot in the stack region.
+fn slot_start(index: u64) -> VirtAddr {
+    VirtAddr::new(AP_STACKS_START + index * AP_SLOT_PAGES * 4096)
+}
+
+/// The ends of the `index`th AP's kernel and Double Fault stacks.
+fn stack_ends(index: u64) -> (VirtAddr, VirtAddr) {
+    let start = slot_start(index);
+    let stack_end = start + (1 + STACK_PAGES) * 4096;
+    let double_fault_stack_end = start + AP_SLOT_PAGES * 4096;
+    (stack_end, double_fault_stack_end)
+}
+
+/// Map both stacks of the `index`th AP, leaving the guard pages unmapped.
+fn map_stacks(
+    index: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = slot_start(index);
+    let stack_pages = 1..1 + STACK_PAGES;
+    let double_fault_stack_pages = 2 + STACK_PAGES..AP_SLOT_PAGES;
+
+    for page_index in stack_pages.chain(double_fault_stack_pages) {
+        let page = Page::containing_address(start + page_index * 4096);
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(())
+}
+
+/// Send the INIT-SIPI-SIPI sequence to the AP with the given APIC ID and wait
+/// for it to come online. Returns `false` if it doesn't.
+fn start_ap(trampoline: &Trampoline, apic_id: u8, index: u64) -> bool {
+    let local_apic = apic::local_apic().expect("local APIC not initialized");
+    let (stack_end, _) = stack_ends(index);
+
+    AP_STARTED.store(false, Ordering::Release);
+    trampoline.prepare(stack_end, index);
+
+    local_apic.send_init(apic_id);
+    delay_us(10_000);
+
+    // The second STARTUP IPI is only there in case the first one got lost,
+    // and is ignored by a CPU that is already running.
+    for _ in 0..2 {
+        local_apic.send_startup(apic_id, trampoline.vector());
+        delay_us(200);
+    }
+
+    // Give it up to 100ms.
+    for _ in 0..100 {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        delay_us(1000);
+    }
+    AP_STARTED.load(Ordering::Acquire)
+}
+
+/// Wait for roughly `us` microseconds.
+///
+/// Writing to the POST code port takes about a microsecond, which is good
+/// enough for the delays in the AP startup sequence and works before the timer
+/// interrupt is set up.
+fn delay_us(us: u32) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..us {
+        unsafe { port.write(0) };
+    }
+}
+
+/// Where each AP ends up after the trampoline, with the index it was started
+/// with.
+extern "C" fn ap_main(index: u64) -> ! {
+    let (_, double_fault_stack_end) = stack_ends(index);
+
+    let tss = gdt::init_ap(double_fault_stack_end);
+    // The BSP is CPU 0.
+    percpu::init_ap(index as usize + 1, tss);
+    interrupts::init_idt();
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.enable();
+    }
+
+    CPUS_ONLINE.fetch_add(1, Ordering::AcqRel);
+    AP_STARTED.store(true, Ordering::Release);
+
+    // Help out with the tasks of the other CPUs. Interrupts are enabled so
+    // that IPIs can wake us up.
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run();
+}
@@ -0,0 +1,212 @@
+use core::ptr;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+// The code an AP starts executing after the STARTUP IPI.
+//
+// It runs in real mode at `page << 12`, where `page` is the SIPI vector, so it
+// gets copied to a page below 1 MiB first. Everything is addressed relative to
+// `ap_trampoline_start`: in real mode through `ds`, and after that through
+// `ebx`, which holds the physical base address. The addresses in the GDT
+// pointer and the far jump targets start out as offsets as well and get the
+// base added by `Trampoline::install`.
+//
+// The AP goes through protected mode to long mode with the BSP's page tables,
+// then calls `ap_entry` with `ap_argument` on the stack at `ap_stack_top`.
+global_asm!(
+    r#"
+.att_syntax prefix
+.pushsection .rodata.ap_trampoline, "a"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    movzx %ax, %ebx
+    shl $4, %ebx
+
+    lgdtl (ap_gdt_pointer - ap_trampoline_start)
+
+    # Enable protected mode.
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+
+    ljmpl *(ap_far_protected - ap_trampoline_start)
+
+.code32
+ap_protected:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    # Enable PAE.
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    mov (ap_cr3 - ap_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+
+    # Enable long mode and no-execute pages in the EFER.
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    # Enable paging and write protection.
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16)), %eax
+    mov %eax, %cr0
+
+    ljmpl *(ap_far_long - ap_trampoline_start)(%ebx)
+
+.code64
+ap_long:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+
+    # The upper half of `rbx` is undefined after the mode switch.
+    mov %ebx, %ebx
+    mov (ap_stack_top - ap_trampoline_start)(%rbx), %rsp
+    mov (ap_argument - ap_trampoline_start)(%rbx), %rdi
+    call *(ap_entry - ap_trampoline_start)(%rbx)
+2:
+    hlt
+    jmp 2b
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff # 32-bit code
+    .quad 0x00cf92000000ffff # data
+    .quad 0x00af9a000000ffff # 64-bit code
+ap_gdt_end:
+.global ap_gdt_pointer
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start
+.global ap_far_protected
+ap_far_protected:
+    .long ap_protected - ap_trampoline_start
+    .word 0x08
+.global ap_far_long
+ap_far_long:
+    .long ap_long - ap_trampoline_start
+    .word 0x18
+
+.balign 8
+.global ap_cr3
+ap_cr3:
+    .quad 0
+.global ap_stack_top
+ap_stack_top:
+    .quad 0
+.global ap_entry
+ap_entry:
+    .quad 0
+.global ap_argument
+ap_argument:
+    .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+.code64
+.popsection
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_gdt_pointer: u8;
+    static ap_far_protected: u8;
+    static ap_far_long: u8;
+    static ap_cr3: u8;
+    static ap_stack_top: u8;
+    static ap_entry: u8;
+    static ap_argument: u8;
+}
+
+/// The offset of a trampoline symbol from the start of the trampoline.
+fn offset_of(symbol: &u8) -> usize {
+    let start = unsafe { &ap_trampoline_start } as *const u8 as usize;
+    symbol as *const u8 as usize - start
+}
+
+/// A copy of the trampoline code in low memory.
+pub(super) struct Trampoline {
+    frame: PhysFrame<Size4KiB>,
+    /// Where `frame` can be written to.
+    base: VirtAddr,
+}
+
+impl Trampoline {
+    /// Copy the trampoline code to `frame` and point it at `entry`.
+    ///
+    /// # Safety
+    /// `frame` must be an unused frame below 1 MiB that is identity mapped,
+    /// and the complete physical memory must be mapped at
+    /// `physical_memory_offset`.
+    pub(super) unsafe fn install(
+        frame: PhysFrame<Size4KiB>,
+        physical_memory_offset: VirtAddr,
+        entry: extern "C" fn(u64) -> !,
+    ) -> Self {
+        let start = &ap_trampoline_start as *const u8;
+        let len = offset_of(&ap_trampoline_end);
+        assert!(len <= 4096, "AP trampoline doesn't fit in a page");
+
+        let base = physical_memory_offset + frame.start_address().as_u64();
+        ptr::copy_nonoverlapping(start, base.as_mut_ptr(), len);
+
+        let trampoline = Trampoline { frame, base };
+
+        // Turn the offsets into physical addresses.
+        let physical_base = frame.start_address().as_u64() as u32;
+        trampoline.add_u32(offset_of(&ap_gdt_pointer) + 2, physical_base);
+        trampoline.add_u32(offset_of(&ap_far_protected), physical_base);
+        trampoline.add_u32(offset_of(&ap_far_long), physical_base);
+
+        // The trampoline loads CR3 while still in 32-bit mode.
+        let (level_4_table, _) = Cr3::read();
+        let cr3 = level_4_table.start_address().as_u64();
+        assert!(cr3 < 1 << 32, "level 4 page table is above 4 GiB");
+
+        trampoline.write_u64(offset_of(&ap_cr3), cr3);
+        trampoline.write_u64(offset_of(&ap_entry), entry as usize as u64);
+        trampoline
+    }
+
+    /// The STARTUP IPI vector that makes an AP run the trampoline.
+    pub(super) fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Set the stack and argument for the next AP that runs the trampoline.
+    pub(super) fn prepare(&self, stack_top: VirtAddr, argument: u64) {
+        unsafe {
+            self.write_u64(offset_of(&ap_stack_top), stack_top.as_u64());
+            self.write_u64(offset_of(&ap_argument), argument);
+        }
+    }
+
+    unsafe fn add_u32(&self, offset: usize, value: u32) {
+        let addr: *mut u32 = (self.base + offset).as_mut_ptr();
+        addr.write_unaligned(addr.read_unaligned() + value);
+    }
+
+    unsafe fn write_u64(&self, offset: usize, value: u64) {
+        let addr: *mut u64 = (self.base + offset).as_mut_ptr();
+        addr.write_volatile(value);
+    }
+}
@@ -0,0 +1,72 @@
+use super::{executor, join, AbortHandle, JoinHandle, Priority, Spawner, Task, TaskHeader, TaskId};
+use alloc::{string::String, sync::Arc};
+use core::future::Future;
+
+/// Configures a task before it gets spawned.
+///
+/// ```ignore
+/// let handle = task::Builder::new()
+///     .name("log compressor")
+///     .priority(Priority::Background)
+///     .spawn(async { compress_logs().await });
+/// ```
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    priority: Priority,
+}
+
+impl Builder {
+    /// Create a builder for a task with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the task a name, which shows up in `Spawner::snapshot`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the priority class the task is scheduled in.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Turn `future` into a `Task`, along with a `JoinHandle` for its output.
+    pub fn build<F>(self, future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let header = Arc::new(TaskHeader::new(TaskId::new(), self.name, self.priority));
+        let (future, handle) = join::joinable(future, AbortHandle::new(header.clone()));
+
+        (Task::with_header(header, future), handle)
+    }
+
+    /// Spawn `future` on `spawner`'s executor.
+    pub fn spawn_on<F>(self, spawner: &Spawner, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.build(future);
+        spawner.spawn_task(task);
+        handle
+    }
+
+    /// Spawn `future` on this CPU's executor.
+    ///
+    /// # Panics
+    /// Panics if no `Executor` has been started with `Executor::run` yet.
+    pub fn spawn<F>(self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let spawner = executor::spawner().expect("task spawned before the executor was started");
+        self.spawn_on(spawner, future)
+    }
+}
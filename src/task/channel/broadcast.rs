@@ -0,0 +1,300 @@
+//! Multi-producer, multi-consumer channels where every receiver sees every
+//! value.
+//!
+//! The channel keeps the last `capacity` values in a ring buffer. Receivers
+//! that fall further behind than that skip ahead and get told how many values
+//! they missed through `RecvError::Lagged`.
+//!
+//! The buffer is allocated up front and its lock is an `IrqSafeMutex`, so
+//! `Sender::send` can be used from interrupt handlers.
+
+use crate::lock::IrqSafeMutex;
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Create a broadcast channel that buffers the last `capacity` values.
+///
+/// # Panics
+/// Panics if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must not be zero");
+
+    let mut buffer = Vec::with_capacity(capacity);
+    buffer.resize_with(capacity, || None);
+
+    let shared = Arc::new(IrqSafeMutex::new(Shared {
+        buffer,
+        tail: 0,
+        senders: 1,
+        receivers: 1,
+        next_receiver_id: 1,
+        waiters: Vec::new(),
+    }));
+
+    let receiver = Receiver {
+        shared: shared.clone(),
+        id: 0,
+        next: 0,
+    };
+    (Sender { shared }, receiver)
+}
+
+/// Returned by `Sender::send` when there are no receivers. Contains the value
+/// that couldn't be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "broadcasting to a channel without receivers")
+    }
+}
+
+/// Returned by `Receiver::recv` when there's no value to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender is gone and there's nothing left to receive.
+    Closed,
+    /// The receiver fell behind and missed this many values. The next call to
+    /// `recv` returns the oldest value that's still buffered.
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "receiving on a closed channel"),
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {} values", n),
+        }
+    }
+}
+
+/// Returned by `Receiver::try_recv` when there's no value to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// There are no new values, but senders still exist.
+    Empty,
+    /// Every sender is gone and there's nothing left to receive.
+    Closed,
+    /// The receiver fell behind and missed this many values.
+    Lagged(u64),
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Closed => write!(f, "receiving on a closed channel"),
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged behind by {} values", n),
+        }
+    }
+}
+
+/// The state shared by every sender and receiver.
+struct Shared<T> {
+    /// The ring buffer. The value with sequence number `n` lives at
+    /// `n % buffer.len()`.
+    buffer: Vec<Option<T>>,
+    /// The sequence number of the next value to be sent.
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+    /// The ID of the next receiver to subscribe.
+    next_receiver_id: u64,
+    /// The IDs and wakers of receivers waiting for the next value, at most one
+    /// per receiver.
+    waiters: Vec<(u64, Waker)>,
+}
+
+impl<T> Shared<T> {
+    fn wake_all(&mut self) {
+        // `drain` keeps the allocation around, so this doesn't free memory.
+        for (_, waker) in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Run `f` with the shared state locked.
+fn with_shared<T, R>(shared: &IrqSafeMutex<Shared<T>>, f: impl FnOnce(&mut Shared<T>) -> R) -> R {
+    f(&mut shared.lock())
+}
+
+/// The sending half of a broadcast channel.
+pub struct Sender<T> {
+    shared: Arc<IrqSafeMutex<Shared<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Send a value to every receiver, returning how many receivers there are.
+    ///
+    /// The oldest buffered value is overwritten if the buffer is full. This
+    /// never blocks or allocates.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        with_shared(&self.shared, |shared| {
+            if shared.receivers == 0 {
+                return Err(SendError(value));
+            }
+
+            let len = shared.buffer.len() as u64;
+            let index = (shared.tail % len) as usize;
+            shared.buffer[index] = Some(value);
+            shared.tail += 1;
+
+            shared.wake_all();
+            Ok(shared.receivers)
+        })
+    }
+
+    /// Create a new receiver that gets every value sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (id, next) = with_shared(&self.shared, |shared| {
+            shared.receivers += 1;
+            let id = shared.next_receiver_id;
+            shared.next_receiver_id += 1;
+            (id, shared.tail)
+        });
+
+        Receiver {
+            shared: self.shared.clone(),
+            id,
+            next,
+        }
+    }
+
+    /// The number of live receivers.
+    pub fn receiver_count(&self) -> usize {
+        with_shared(&self.shared, |shared| shared.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        with_shared(&self.shared, |shared| shared.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        with_shared(&self.shared, |shared| {
+            shared.senders -= 1;
+            if shared.senders == 0 {
+                shared.wake_all();
+            }
+        });
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender").finish()
+    }
+}
+
+/// The receiving half of a broadcast channel.
+pub struct Receiver<T> {
+    shared: Arc<IrqSafeMutex<Shared<T>>>,
+    /// Identifies the receiver's entry in `Shared::waiters`.
+    id: u64,
+    /// The sequence number of the next value we want.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Wait for the next value.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Receive the next value if there is one.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let next = &mut self.next;
+        with_shared(&self.shared, |shared| Self::try_recv_locked(next, shared))
+    }
+
+    fn try_recv_locked(next: &mut u64, shared: &mut Shared<T>) -> Result<T, TryRecvError> {
+        if *next == shared.tail {
+            return if shared.senders == 0 {
+                Err(TryRecvError::Closed)
+            } else {
+                Err(TryRecvError::Empty)
+            };
+        }
+
+        let len = shared.buffer.len() as u64;
+        let oldest = shared.tail.saturating_sub(len);
+        if *next < oldest {
+            let missed = oldest - *next;
+            *next = oldest;
+            return Err(TryRecvError::Lagged(missed));
+        }
+
+        let value = shared.buffer[(*next % len) as usize].clone();
+        *next += 1;
+        Ok(value.expect("broadcast slot should be filled"))
+    }
+
+    /// Poll for the next value.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let id = self.id;
+        let next = &mut self.next;
+
+        with_shared(&self.shared, |shared| {
+            match Self::try_recv_locked(next, shared) {
+                Ok(value) => Poll::Ready(Ok(value)),
+                Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Lagged(n)) => Poll::Ready(Err(RecvError::Lagged(n))),
+                Err(TryRecvError::Empty) => {
+                    let waker = cx.waker();
+                    match shared.waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+                        Some((_, registered)) if registered.will_wake(waker) => {}
+                        Some((_, registered)) => *registered = waker.clone(),
+                        None => shared.waiters.push((id, waker.clone())),
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        with_shared(&self.shared, |shared| {
+            shared.receivers -= 1;
+            // Don't keep the receiver's task alive, or wake it for nothing.
+            shared.waiters.retain(|(waiter, _)| *waiter != id);
+        });
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
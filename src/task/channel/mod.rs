@@ -0,0 +1,8 @@
+//! Async channels for passing values between tasks.
+//!
+//! The sending halves that are marked as such never block or allocate, so
+//! they can be used from interrupt handlers to hand work off to a task.
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
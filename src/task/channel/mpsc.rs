@@ -0,0 +1,353 @@
+//! Multi-producer, single-consumer channels.
+//!
+//! `channel` creates a bounded channel whose `Sender::try_send` is lock-free
+//! and never allocates, which makes it usable from interrupt handlers.
+//! `unbounded_channel` never makes senders wait, but sending may allocate.
+
+use crate::task::sync::Notify;
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+/// Create a bounded channel that holds at most `capacity` values.
+///
+/// # Panics
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(Chan::new(Queue::Bounded(ArrayQueue::new(capacity))));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Create a channel without a size limit.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Arc::new(Chan::new(Queue::Unbounded(SegQueue::new())));
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Returned by `send` when the receiver is gone. Contains the value that
+/// couldn't be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+/// Returned by `Sender::try_send` when the value couldn't be sent right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Closed(_) => write!(f, "sending on a closed channel"),
+        }
+    }
+}
+
+/// Returned by `Receiver::try_recv` when there's no value to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel is empty, but senders still exist.
+    Empty,
+    /// The channel is empty and every sender is gone.
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Closed => write!(f, "receiving on a closed channel"),
+        }
+    }
+}
+
+/// The values in flight.
+enum Queue<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+/// The state shared by both ends of a channel.
+struct Chan<T> {
+    queue: Queue<T>,
+    /// The number of live senders.
+    senders: AtomicUsize,
+    /// Set once the receiver is dropped or closed.
+    rx_closed: AtomicBool,
+    /// Wakes the receiver when a value arrives or the last sender leaves.
+    rx_waker: AtomicWaker,
+    /// Wakes senders waiting for space in a bounded channel.
+    send_ready: Notify,
+}
+
+impl<T> Chan<T> {
+    fn new(queue: Queue<T>) -> Self {
+        Chan {
+            queue,
+            senders: AtomicUsize::new(1),
+            rx_closed: AtomicBool::new(false),
+            rx_waker: AtomicWaker::new(),
+            send_ready: Notify::new(),
+        }
+    }
+
+    /// Push a value into the queue and wake the receiver.
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.rx_closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+
+        match &self.queue {
+            Queue::Bounded(queue) => queue.push(value).map_err(TrySendError::Full)?,
+            Queue::Unbounded(queue) => queue.push(value),
+        }
+
+        self.rx_waker.wake();
+        Ok(())
+    }
+
+    /// Pop a value out of the queue, making room for waiting senders.
+    fn pop(&self) -> Option<T> {
+        match &self.queue {
+            Queue::Bounded(queue) => {
+                let value = queue.pop()?;
+                self.send_ready.notify_one();
+                Some(value)
+            }
+            Queue::Unbounded(queue) => queue.pop(),
+        }
+    }
+
+    fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn drop_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // That was the last one; let the receiver know.
+            self.rx_waker.wake();
+        }
+    }
+
+    fn close_rx(&self) {
+        self.rx_closed.store(true, Ordering::Release);
+        self.send_ready.notify_waiters();
+        // Leave a notification around for a sender that's about to wait.
+        self.send_ready.notify_one();
+    }
+}
+
+/// The sending half of a bounded channel, created by `channel`.
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a value, waiting for space in the channel if it's full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = value;
+
+        loop {
+            match self.chan.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(returned)) => {
+                    value = returned;
+                    self.chan.send_ready.notified().await;
+                }
+                Err(TrySendError::Closed(returned)) => {
+                    // Pass the wakeup on to the next sender waiting on a
+                    // closed channel.
+                    self.chan.send_ready.notify_one();
+                    return Err(SendError(returned));
+                }
+            }
+        }
+    }
+
+    /// Send a value if there's space in the channel right now.
+    ///
+    /// This never blocks or allocates, so it's safe to call from interrupt
+    /// handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+
+    /// Returns `true` if the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.chan.rx_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// The sending half of an unbounded channel, created by `unbounded_channel`.
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Send a value. This never waits.
+    ///
+    /// The queue allocates more memory as it grows, so this shouldn't be used
+    /// from interrupt handlers.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.try_send(value).map_err(|err| match err {
+            TrySendError::Full(value) | TrySendError::Closed(value) => SendError(value),
+        })
+    }
+
+    /// Returns `true` if the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.chan.rx_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for UnboundedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnboundedSender")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// The receiving half of a channel.
+///
+/// Also implements `Stream`, which ends once every sender is gone and the
+/// channel has been drained.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next value. Returns `None` once every sender is gone and
+    /// the channel is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Receive a value if one is available right now.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.chan.pop() {
+            return Ok(value);
+        }
+
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // A value might have snuck in right before the last sender left.
+            self.chan.pop().ok_or(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Stop accepting new values. Values that were already sent can still be
+    /// received.
+    pub fn close(&mut self) {
+        self.chan.close_rx();
+    }
+
+    /// Poll for the next value.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        // fast path
+        if let Some(value) = self.chan.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.chan.rx_waker.register(cx.waker());
+
+        match self.try_recv() {
+            Ok(value) => {
+                self.chan.rx_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.close_rx();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver").finish()
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,204 @@
+//! Channels for sending a single value.
+//!
+//! `Sender::send` only touches atomics, so it's safe to call from interrupt
+//! handlers.
+
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+/// Create a channel for sending a single value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: AtomicU8::new(0),
+        value: UnsafeCell::new(None),
+        rx_waker: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            inner: Some(inner.clone()),
+        },
+        Receiver { inner },
+    )
+}
+
+/// Returned by the `Receiver` when the `Sender` was dropped without sending
+/// anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "oneshot sender dropped without sending a value")
+    }
+}
+
+/// Returned by `Receiver::try_recv` when there's no value to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value has been sent yet.
+    Empty,
+    /// The `Sender` was dropped without sending anything.
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "no value sent yet"),
+            TryRecvError::Closed => write!(f, "oneshot sender dropped without sending a value"),
+        }
+    }
+}
+
+/// Set in `Inner::state` once the value has been written.
+const VALUE_SENT: u8 = 1 << 0;
+/// Set in `Inner::state` once the sender is gone.
+const TX_DROPPED: u8 = 1 << 1;
+/// Set in `Inner::state` once the receiver is gone.
+const RX_DROPPED: u8 = 1 << 2;
+
+/// The state shared by both ends of the channel.
+struct Inner<T> {
+    state: AtomicU8,
+    /// Written by the sender before it sets `VALUE_SENT`, and only touched by
+    /// the receiver after that.
+    value: UnsafeCell<Option<T>>,
+    rx_waker: AtomicWaker,
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    /// Take the value out, if it has been sent. Must only be called by the
+    /// receiver.
+    unsafe fn take_value(&self, state: u8) -> Option<T> {
+        if state & VALUE_SENT != 0 {
+            (*self.value.get()).take()
+        } else {
+            None
+        }
+    }
+}
+
+/// The sending half of a oneshot channel.
+pub struct Sender<T> {
+    /// Taken out when the value is sent.
+    inner: Option<Arc<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send the value, consuming the sender. Gives the value back if the
+    /// receiver is gone.
+    ///
+    /// This never blocks or allocates, so it's safe to call from interrupt
+    /// handlers.
+    pub fn send(mut self, value: T) -> Result<(), T> {
+        let inner = self.inner.take().expect("oneshot sender already used");
+
+        if inner.state.load(Ordering::Acquire) & RX_DROPPED != 0 {
+            return Err(value);
+        }
+
+        // Nobody else touches the value until we set `VALUE_SENT`.
+        unsafe { *inner.value.get() = Some(value) };
+
+        let state = inner
+            .state
+            .fetch_or(VALUE_SENT | TX_DROPPED, Ordering::AcqRel);
+        if state & RX_DROPPED != 0 {
+            // The receiver left while we were busy, so the value is ours again.
+            let value = unsafe { (*inner.value.get()).take() };
+            return Err(value.expect("oneshot value vanished"));
+        }
+
+        inner.rx_waker.wake();
+        Ok(())
+    }
+
+    /// Returns `true` if the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        match &self.inner {
+            Some(inner) => inner.state.load(Ordering::Acquire) & RX_DROPPED != 0,
+            None => true,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            inner.state.fetch_or(TX_DROPPED, Ordering::AcqRel);
+            inner.rx_waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// The receiving half of a oneshot channel. Await it to get the value.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the value if it has been sent already.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.inner.state.load(Ordering::Acquire);
+
+        // We're the receiver.
+        match unsafe { self.inner.take_value(state) } {
+            Some(value) => Ok(value),
+            None if state & TX_DROPPED != 0 => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // fast path
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.inner.rx_waker.register(cx.waker());
+
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.state.fetch_or(RX_DROPPED, Ordering::AcqRel);
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver").finish()
+    }
+}
@@ -1,83 +1,277 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{
+    run_queue::RunQueue, stats, JoinHandle, Priority, Task, TaskHeader, TaskId, TaskSnapshot,
+    WakeSource,
+};
+use crate::{
+    apic,
+    lock::IrqSafeMutex,
+    percpu::{self, PerCpu},
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    future::Future,
+    mem::{self, ManuallyDrop},
+    ptr,
+    sync::atomic::{fence, AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::SegQueue;
+use x86_64::instructions::interrupts;
 
-/// A better `Future` executor.
+/// The maximum number of tasks polled in a single scheduling round.
+const POLL_BUDGET: usize = 64;
+
+/// The workers of the executors that are running, at most one per CPU. Idle
+/// executors steal tasks from these.
+///
+/// Workers are never removed, since `Executor::run` doesn't return.
+static RUNNING: IrqSafeMutex<Vec<Arc<Worker>>> = IrqSafeMutex::new(Vec::new());
+
+/// Get the header of the task that is currently being polled on this CPU.
+fn current_task() -> Option<Arc<TaskHeader>> {
+    let current = percpu::current().current_task.load(Ordering::Acquire);
+
+    if current.is_null() {
+        None
+    } else {
+        // The executor keeps the header alive while the task is being polled,
+        // so we can safely clone the `Arc` it points to. `ManuallyDrop` makes
+        // sure we don't release the executor's reference.
+        let header = ManuallyDrop::new(unsafe { Arc::from_raw(current) });
+        Some(Arc::clone(&header))
+    }
+}
+
+/// Get the ID of the task that is currently being polled on this CPU.
+fn current_task_id() -> Option<TaskId> {
+    let current = percpu::current().current_task.load(Ordering::Acquire);
+
+    // See `current_task` for why the header is still alive.
+    unsafe { current.as_ref() }.map(|header| header.id)
+}
+
+/// Get a handle to the `Spawner` of the executor running on this CPU.
+///
+/// Returns `None` if `Executor::run` hasn't been called on this CPU yet.
+pub fn spawner() -> Option<&'static Spawner> {
+    percpu::current().spawner.try_get().ok()
+}
+
+/// A better `Future` executor, meant to be run on every CPU.
+///
+/// Each executor has its own run queue, and polls the tasks spawned on it
+/// until an executor that ran out of work steals half of its ready tasks.
+/// Wakeups go to the run queue of whichever executor owns the task at the
+/// time, and get that executor's CPU out of `hlt` with an inter-processor
+/// interrupt if needed.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    worker: Arc<Worker>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            worker: Arc::new(Worker::new()),
         }
     }
 
-    /// Spawn a new `Task` in the executor.
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with the same ID already in tasks")
+    /// Get a `Spawner` that can add tasks to this executor, even while it's
+    /// running.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            worker: self.worker.clone(),
         }
+    }
+
+    /// Take a snapshot of every task in the executor. See
+    /// `Spawner::snapshot`.
+    pub fn snapshot(&self) -> Vec<TaskSnapshot> {
+        self.worker.snapshot()
+    }
+
+    /// Spawn a future in the executor, returning a `JoinHandle` that resolves
+    /// to the future's output once it completes.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task);
+        handle
+    }
 
-        self.task_queue.push(task_id).expect("queue full");
+    /// Spawn an already-constructed `Task` in the executor.
+    pub fn spawn_task(&mut self, task: Task) {
+        self.worker.spawn_task(task);
     }
 
-    /// Run the executor and its tasks.
+    /// Run the executor and its tasks on the current CPU.
+    ///
+    /// This also makes the executor's `Spawner` available to everything
+    /// running on this CPU through `spawner()` and `task::spawn`, and lets the
+    /// executors of other CPUs steal its tasks.
+    ///
+    /// # Panics
+    /// Panics if another executor is already running on this CPU.
     pub fn run(&mut self) -> ! {
+        let cpu = percpu::current();
+        cpu.spawner
+            .try_init_once(|| self.spawner())
+            .expect("only one executor can run per CPU");
+        self.worker.cpu.init_once(|| cpu);
+        RUNNING.lock().push(self.worker.clone());
+
         loop {
+            self.spawn_new_tasks();
             self.run_ready_tasks();
+            if self.is_idle() {
+                self.steal();
+            }
             self.sleep_if_idle();
         }
     }
 
+    /// Run a single scheduling round without halting: pick up the tasks
+    /// sent through a `Spawner` and poll up to `POLL_BUDGET` ready tasks.
+    ///
+    /// `run` does this in a loop. This is for driving an executor by hand,
+    /// like in tests.
+    pub fn run_once(&mut self) {
+        self.spawn_new_tasks();
+        self.run_ready_tasks();
+    }
+
+    /// Move any tasks sent through a `Spawner` into the executor.
+    fn spawn_new_tasks(&mut self) {
+        self.worker.spawn_new_tasks();
+    }
+
+    /// Returns `true` if there are no tasks to poll or spawn.
+    fn is_idle(&self) -> bool {
+        self.worker.is_idle()
+    }
+
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
+        use x86_64::instructions::interrupts::enable_interrupts_and_hlt;
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+
+        // Whoever queues a task from now on sends us an IPI. See
+        // `Worker::notify`.
+        self.worker.sleeping.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+
+        if self.is_idle() {
             enable_interrupts_and_hlt();
         } else {
             interrupts::enable();
         }
+        self.worker.sleeping.store(false, Ordering::SeqCst);
     }
 
-    /// Run all currently ready tasks.
+    /// Run one scheduling round.
+    ///
+    /// Each priority class that has a ready task gets one poll up front, so
+    /// that lower classes keep making progress even if higher ones never run
+    /// dry. The rest of the round's `POLL_BUDGET` goes to whichever ready
+    /// task has the highest priority. Tasks woken during the round go to the
+    /// back of their class's queue.
     fn run_ready_tasks(&mut self) {
-        // Destructure `self` to avoid borrow checker errors:
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // Task no longer exists!
-            };
-
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-
-            let mut context = Context::from_waker(waker);
-
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // Task done! -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+        self.worker.collect_woken_tasks();
+        if self.worker.ready_count() > 1 {
+            // There's more than one task to do, so get an idle CPU to help.
+            notify_idle_worker();
+        }
+
+        for priority in Priority::ALL.iter() {
+            self.worker.collect_woken_tasks();
+
+            if let Some(header) = self.worker.pop_ready(Some(*priority)) {
+                self.poll_task(header);
+            }
+        }
+
+        for _ in Priority::COUNT..POLL_BUDGET {
+            self.worker.collect_woken_tasks();
+
+            match self.worker.pop_ready(None) {
+                Some(header) => self.poll_task(header),
+                None => break,
+            }
+        }
+    }
+
+    /// Steal tasks from the first executor of another CPU that has any.
+    fn steal(&self) {
+        let running = RUNNING.lock();
+        let victims = running
+            .iter()
+            .filter(|victim| !Arc::ptr_eq(victim, &self.worker));
+
+        for victim in victims {
+            if self.worker.steal_from(victim) {
+                break;
+            }
+        }
+    }
+
+    /// Poll a single task that was taken out of a ready queue.
+    fn poll_task(&mut self, header: Arc<TaskHeader>) {
+        header.unschedule();
+
+        if header.is_aborted() {
+            // Dropping the future cancels the task and wakes its joiners.
+            header.release();
+            unregister(&header);
+            return;
+        }
+
+        // If the task was woken and stolen while another CPU was polling it,
+        // that poll might still be going on. Try again later in that case.
+        let mut future = match header.future.try_lock() {
+            Some(future) => future,
+            None => {
+                header.wake();
+                return;
+            }
+        };
+
+        let waker = header
+            .waker
+            .lock()
+            .get_or_insert_with(|| TaskWaker::new(header.clone()))
+            .clone();
+        let mut context = Context::from_waker(&waker);
+
+        let cpu = percpu::current();
+        cpu.current_task
+            .store(Arc::as_ptr(&header) as *mut _, Ordering::Release);
+        let start = stats::cycles();
+        let poll = match future.as_mut() {
+            Some(future) => future.as_mut().poll(&mut context),
+            None => Poll::Ready(()), // Task no longer exists!
+        };
+        header
+            .stats
+            .record_poll(stats::cycles().wrapping_sub(start));
+        cpu.current_task.store(ptr::null_mut(), Ordering::Release);
+        cpu.stats().count_task_poll();
+        drop(future);
+
+        match poll {
+            Poll::Ready(()) => {
+                // Task done! -> drop its future and cached waker
+                header.release();
+                unregister(&header);
             }
+            Poll::Pending => {}
         }
     }
 }
@@ -88,24 +282,307 @@ impl Default for Executor {
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // Spawned tasks keep their executor alive through `TaskHeader::home`,
+        // so cancel them all to break the cycle. Only executors that never
+        // ran can get dropped.
+        let registry = mem::take(&mut *self.worker.registry.lock());
+        for header in registry.values() {
+            header.release();
+        }
+
+        self.worker.clear();
+    }
+}
+
+/// Remove a finished task from the registry of the executor it was spawned
+/// on.
+fn unregister(header: &TaskHeader) {
+    if let Ok(home) = header.home.try_get() {
+        home.unregister(header.id);
+    }
+}
+
+/// Get some other CPU that is halted in `Executor::sleep_if_idle` to come
+/// looking for tasks to steal.
+fn notify_idle_worker() {
+    let current = percpu::current().id();
+
+    let running = RUNNING.lock();
+    let idle = running.iter().find(|worker| {
+        worker.sleeping.load(Ordering::SeqCst)
+            && worker
+                .cpu
+                .try_get()
+                .map_or(false, |cpu| cpu.id() != current)
+    });
+
+    if let Some(worker) = idle {
+        worker.notify();
+    }
+}
+
+/// The part of an executor that is shared with its `Spawner`s, the wakers of
+/// its tasks and the executors of other CPUs.
+pub(super) struct Worker {
+    /// Tasks that haven't been picked up by the executor yet.
+    new_tasks: SegQueue<Task>,
+    /// Tasks woken up since the executor last checked.
+    woken: RunQueue,
+    /// Tasks taken out of `woken` that are waiting for their turn, one queue
+    /// per `Priority`. Other executors steal from these.
+    ready: IrqSafeMutex<[VecDeque<Arc<TaskHeader>>; Priority::COUNT]>,
+    /// The headers of every task spawned on the executor that hasn't finished
+    /// yet, for diagnostics.
+    registry: IrqSafeMutex<BTreeMap<TaskId, Arc<TaskHeader>>>,
+    /// The CPU the executor runs on, once it's running.
+    cpu: OnceCell<&'static PerCpu>,
+    /// Set while the executor's CPU is halted, waiting for an interrupt.
+    sleeping: AtomicBool,
+}
+
+impl Worker {
+    fn new() -> Self {
+        Worker {
+            new_tasks: SegQueue::new(),
+            woken: RunQueue::new(),
+            ready: IrqSafeMutex::new([VecDeque::new(), VecDeque::new(), VecDeque::new()]),
+            registry: IrqSafeMutex::new(BTreeMap::new()),
+            cpu: OnceCell::uninit(),
+            sleeping: AtomicBool::new(false),
+        }
+    }
+
+    /// Take ownership of a new task and queue its first poll.
+    fn spawn_task(self: &Arc<Self>, task: Task) {
+        let header = task.into_header();
+
+        header
+            .home
+            .try_init_once(|| self.clone())
+            .expect("task was already spawned");
+
+        if header.is_aborted() {
+            // Aborted before it even got here; dropping the future cancels it.
+            header.release();
+            return;
+        }
+
+        header.stats.record_spawn();
+        if self
+            .registry
+            .lock()
+            .insert(header.id, header.clone())
+            .is_some()
+        {
+            panic!("task with the same ID already in registry")
+        }
+
+        header
+            .worker
+            .store(Arc::as_ptr(self) as *mut _, Ordering::Release);
+        if header.schedule() {
+            self.push_ready(header);
+        }
+    }
+
+    /// Move any tasks sent through a `Spawner` into the ready queues.
+    fn spawn_new_tasks(self: &Arc<Self>) {
+        while let Some(task) = self.new_tasks.pop() {
+            self.spawn_task(task);
+        }
+    }
+
+    /// Queue a task that was woken up, getting the executor's CPU out of `hlt`
+    /// if needed.
+    ///
+    /// Never blocks or allocates, so it's safe to call in interrupt handlers.
+    pub(super) fn push_woken(&self, header: Arc<TaskHeader>) {
+        self.woken.push(header);
+        self.notify();
+    }
+
+    /// Send an IPI to the executor's CPU if it's halted.
+    fn notify(&self) {
+        // Pairs with the fence in `Executor::sleep_if_idle`: either the
+        // executor sees the new task before it halts, or we see it sleeping.
+        fence(Ordering::SeqCst);
+        if !self.sleeping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let cpu = match self.cpu.try_get() {
+            Ok(cpu) => cpu,
+            Err(_) => return,
+        };
+
+        // If that's our own CPU, we're in an interrupt handler, and the CPU
+        // leaves `hlt` once it returns anyway.
+        if cpu.id() == percpu::current().id() {
+            return;
+        }
+
+        if let Some(local_apic) = apic::local_apic() {
+            local_apic.send_interrupt(cpu.apic_id(), apic::WAKEUP_VECTOR);
+        }
+    }
+
+    /// Sort everything that was woken since we last checked into the ready
+    /// queue of its priority class.
+    fn collect_woken_tasks(&self) {
+        for header in self.woken.take_all() {
+            self.push_ready(header);
+        }
+    }
+
+    fn push_ready(&self, header: Arc<TaskHeader>) {
+        self.ready.lock()[header.priority.as_usize()].push_back(header);
+    }
+
+    /// Take the next task out of the ready queue of `priority`, or out of the
+    /// highest priority class that has one if `priority` is `None`.
+    fn pop_ready(&self, priority: Option<Priority>) -> Option<Arc<TaskHeader>> {
+        let mut ready = self.ready.lock();
+        match priority {
+            Some(priority) => ready[priority.as_usize()].pop_front(),
+            None => ready.iter_mut().find_map(VecDeque::pop_front),
+        }
+    }
+
+    /// The number of tasks waiting in the ready queues.
+    fn ready_count(&self) -> usize {
+        self.ready.lock().iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns `true` if there are no tasks to poll or spawn.
+    fn is_idle(&self) -> bool {
+        self.ready_count() == 0 && self.woken.is_empty() && self.new_tasks.is_empty()
+    }
+
+    /// Move the newer half of each of `victim`'s ready queues, rounded up,
+    /// over to this worker. Returns `true` if there was anything to steal.
+    ///
+    /// Must only be called by this worker's executor while it's running,
+    /// since the stolen tasks get woken into its run queue from now on.
+    fn steal_from(&self, victim: &Arc<Worker>) -> bool {
+        victim.spawn_new_tasks();
+        victim.collect_woken_tasks();
+
+        let stolen: Vec<_> = victim
+            .ready
+            .lock()
+            .iter_mut()
+            .flat_map(|queue| queue.split_off(queue.len() / 2))
+            .collect();
+
+        let found = !stolen.is_empty();
+        for header in stolen {
+            // The task is still marked as scheduled, so nobody queues it
+            // anywhere before it's in our ready queue.
+            header
+                .worker
+                .store(self as *const _ as *mut _, Ordering::Release);
+            self.push_ready(header);
+        }
+        found
+    }
+
+    fn snapshot(&self) -> Vec<TaskSnapshot> {
+        // Clone the headers first so that we don't allocate strings with the
+        // registry locked.
+        let headers: Vec<_> = self.registry.lock().values().cloned().collect();
+        headers.iter().map(|header| header.snapshot()).collect()
+    }
+
+    fn unregister(&self, id: TaskId) {
+        self.registry.lock().remove(&id);
+    }
+
+    /// Drop every task that is still queued.
+    fn clear(&self) {
+        let ready = mem::take(&mut *self.ready.lock());
+        drop(ready);
+        for _ in self.woken.take_all() {}
+        while self.new_tasks.pop().is_some() {}
+    }
+}
+
+/// A cloneable handle for spawning tasks onto an `Executor`, usable from inside
+/// the executor's own tasks.
+///
+/// New tasks are pushed onto a lock-free queue that the executor drains
+/// between polls, so spawning never blocks and won't deadlock if it happens in
+/// an interrupt handler. Keep in mind that turning a future into a `Task`
+/// allocates, though.
+#[derive(Clone)]
+pub struct Spawner {
+    worker: Arc<Worker>,
+}
+
+impl Spawner {
+    /// Spawn a future on the executor, returning a `JoinHandle` that resolves
+    /// to the future's output once it completes.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task);
+        handle
+    }
+
+    /// Spawn an already-constructed `Task` on the executor.
+    ///
+    /// If this is called from inside a task, the new task becomes a child of
+    /// that task and is aborted along with it.
+    pub fn spawn_task(&self, task: Task) {
+        if let Some(parent) = current_task() {
+            parent.add_child(&task.header);
+        }
+
+        self.worker.new_tasks.push(task);
+        self.worker.notify();
+    }
+
+    /// Take a snapshot of the metadata and runtime statistics of every task
+    /// that was spawned on the executor and hasn't finished yet, ordered by
+    /// task ID. This includes tasks that other CPUs have stolen since.
+    ///
+    /// Tasks that are still waiting to be picked up by the executor aren't
+    /// included.
+    pub fn snapshot(&self) -> Vec<TaskSnapshot> {
+        self.worker.snapshot()
+    }
+}
+
 /// A custom waker implementation.
+///
+/// Waking a task that is already queued does nothing, so spurious wakeups are
+/// cheap and the run queue never holds more entries than there are tasks.
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    header: Arc<TaskHeader>,
 }
 
 impl TaskWaker {
     #[allow(clippy::new_ret_no_self)]
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn new(header: Arc<TaskHeader>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { header }))
     }
 
-    /// Wake up a task
+    /// Wake up a task, remembering who woke it.
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        let source = if crate::interrupts::in_interrupt() {
+            WakeSource::Interrupt
+        } else if let Some(id) = current_task_id() {
+            WakeSource::Task(id)
+        } else {
+            WakeSource::Other
+        };
+        self.header.stats.record_wake(source);
+
+        self.header.wake();
     }
 }
 
@@ -0,0 +1,197 @@
+use super::TaskHeader;
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+/// The reason a `JoinHandle` resolved without the task's output.
+///
+/// Note that there's no "panicked" variant: the kernel is built with
+/// `panic-strategy: abort`, so a panicking task takes the whole kernel down
+/// with it and nobody is left to observe the panic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task's future was dropped before it ran to completion.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+/// The state shared between a running task and its `JoinHandle`.
+enum JoinState<T> {
+    /// The task hasn't finished yet. Holds the waker of whoever is awaiting
+    /// the `JoinHandle`, if anyone.
+    Running(Option<Waker>),
+    /// The task finished and its output is waiting to be picked up.
+    Finished(T),
+    /// The task was dropped before finishing.
+    Cancelled,
+    /// The output has already been handed to the `JoinHandle`.
+    Consumed,
+}
+
+impl<T> JoinState<T> {
+    /// Move the state out of a `Running` state into `new_state`, waking anyone
+    /// waiting on the `JoinHandle`. Does nothing if the task is already done.
+    fn resolve(&mut self, new_state: JoinState<T>) {
+        if let JoinState::Running(waker) = self {
+            let waker = waker.take();
+            *self = new_state;
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// An owned permission to await the output of a spawned task.
+///
+/// Dropping a `JoinHandle` detaches the task: it keeps running, but its output
+/// is dropped once it completes.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+    abort_handle: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Abort the task. See `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+
+    /// Get a handle that can abort the task without being able to await it.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort_handle.clone()
+    }
+
+    /// Returns `true` if the task has finished, either by running to completion
+    /// or by being cancelled.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.state.lock(), JoinState::Running(_))
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+
+        match mem::replace(&mut *state, JoinState::Consumed) {
+            JoinState::Running(_) => {
+                *state = JoinState::Running(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            JoinState::Finished(output) => Poll::Ready(Ok(output)),
+            JoinState::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            JoinState::Consumed => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+/// A handle that can be used to abort a task.
+#[derive(Clone)]
+pub struct AbortHandle {
+    header: Arc<TaskHeader>,
+}
+
+impl AbortHandle {
+    pub(crate) fn new(header: Arc<TaskHeader>) -> Self {
+        AbortHandle { header }
+    }
+
+    /// Abort the task.
+    ///
+    /// The executor drops the task's future (and its cached waker) the next
+    /// time it looks at the task, which makes any `JoinHandle` for it resolve
+    /// to `JoinError::Cancelled`. Every task that was spawned from inside the
+    /// aborted task is aborted as well.
+    ///
+    /// Aborting a task that already finished does nothing.
+    pub fn abort(&self) {
+        self.header.abort();
+    }
+
+    /// Returns `true` if the task has been aborted.
+    pub fn is_aborted(&self) -> bool {
+        self.header.is_aborted()
+    }
+}
+
+impl fmt::Debug for AbortHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AbortHandle")
+            .field("task_id", &self.header.id)
+            .field("aborted", &self.is_aborted())
+            .finish()
+    }
+}
+
+/// Lives inside a task's future and reports the task's fate to its
+/// `JoinHandle`.
+///
+/// If the future gets dropped before `complete` is called (say, because the
+/// executor threw the task away), the `Drop` impl marks the task as cancelled.
+struct Completion<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> Completion<T> {
+    /// Hand the task's output over to the `JoinHandle`.
+    fn complete(self, output: T) {
+        self.state.lock().resolve(JoinState::Finished(output));
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.state.lock().resolve(JoinState::Cancelled);
+    }
+}
+
+/// Wrap `future` in a future with output `()` that reports its output to the
+/// returned `JoinHandle`. `abort_handle` must belong to the task that the
+/// wrapped future is going to be run in.
+pub(crate) fn joinable<F>(
+    future: F,
+    abort_handle: AbortHandle,
+) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(Mutex::new(JoinState::Running(None)));
+    let completion = Completion {
+        state: state.clone(),
+    };
+
+    let task = async move {
+        let output = future.await;
+        completion.complete(output);
+    };
+
+    let handle = JoinHandle {
+        state,
+        abort_handle,
+    };
+    (task, handle)
+}
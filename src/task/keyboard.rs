@@ -1,7 +1,34 @@
-use crate::{print, println};
+//! The keyboard task and the stream of scancodes feeding it.
+//!
+//! `run` is the only consumer of the scancodes. It decodes them into
+//! `KeyEvent`s and broadcasts those to every `KeyEvents` stream, so any number
+//! of tasks can get keyboard input through `subscribe`.
+//!
+//! The layout can be changed at runtime with `set_layout`. The keyboard LEDs
+//! follow the state of the lock keys. Shift+PageUp and Shift+PageDown scroll
+//! through the VGA console's scrollback and aren't broadcast.
+//!
+//! The keyboard is switched to scancode set 2 with the controller's
+//! translation turned off, so scancodes arrive as the keyboard sends them.
+//! Keyboards that refuse stay on translated set 1.
+
+mod decoder;
+mod scancode;
+
+pub use decoder::{Key, KeyDecoder, KeyEvent, Layout, LockState, Modifiers};
+pub use pc_keyboard::{KeyCode, KeyState};
+pub use scancode::{MediaKey, PhysicalKey, ScancodeSet};
+
+use crate::{
+    print, println,
+    ps2::{self, Ps2Error, Ps2Port, ACK, RESEND},
+    task::channel::broadcast::{self, RecvError, TryRecvError},
+    vga_buffer::{BUFFER_HEIGHT, WRITER},
+};
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
     task::{Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
@@ -9,26 +36,279 @@ use futures_util::{
     stream::{Stream, StreamExt},
     task::AtomicWaker,
 };
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-/// Print any keypresses to the screen.
-pub async fn print_keypresses() {
+/// Where `run` sends the decoded key events.
+static KEY_EVENTS: OnceCell<broadcast::Sender<KeyEvent>> = OnceCell::uninit();
+
+/// How many key events a subscriber can fall behind before it misses some.
+const KEY_EVENT_CAPACITY: usize = 64;
+
+/// The layout the keyboard task decodes keys with.
+static LAYOUT: AtomicU8 = AtomicU8::new(0);
+
+/// The keyboard's command to set its LEDs, followed by a byte of LED bits.
+const SET_LEDS: u8 = 0xed;
+/// The keyboard's command to select a scancode set, followed by its number.
+const SET_SCANCODE_SET: u8 = 0xf0;
+/// The keyboard's command to set the typematic delay and rate, followed by a
+/// byte that encodes both.
+const SET_TYPEMATIC: u8 = 0xf3;
+
+/// The layout keys are currently decoded with.
+pub fn layout() -> Layout {
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+/// Decode keys with `layout` from now on.
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout.as_u8(), Ordering::Relaxed);
+}
+
+/// Make held keys repeat `rate` times a second, starting `delay_ms`
+/// milliseconds after they're pressed.
+///
+/// Keyboards support delays from 250 to 1000ms in steps of 250ms and rates
+/// from 2 to 30 repeats a second, so the closest of those is used.
+pub fn set_key_repeat(delay_ms: u16, rate: u8) -> Result<(), Ps2Error> {
+    ps2::command(
+        Ps2Port::First,
+        &[SET_TYPEMATIC, typematic_byte(delay_ms, rate)],
+    )
+}
+
+/// The data byte of the "set typematic" command for the delay and rate
+/// closest to `delay_ms` and `rate`.
+fn typematic_byte(delay_ms: u16, rate: u8) -> u8 {
+    // Bits 5 and 6 select a delay of 250ms times one more than their value.
+    let delay = (delay_ms.saturating_add(125) / 250).max(1).min(4) as u8 - 1;
+
+    // A key repeats every (8 + A) * 2^B / 240 seconds, with A in bits 0 to 2
+    // and B in bits 3 and 4.
+    let rate = i32::from(rate.max(2).min(30));
+    let period = |bits: u8| (8 + i32::from(bits & 0b111)) << (bits >> 3);
+    let rate_bits = (0..32)
+        .min_by_key(|&bits| (period(bits) * rate - 240).abs())
+        .unwrap_or(0);
+
+    (delay << 5) | rate_bits
+}
+
+fn key_event_sender() -> &'static broadcast::Sender<KeyEvent> {
+    KEY_EVENTS.get_or_init(|| broadcast::channel(KEY_EVENT_CAPACITY).0)
+}
+
+/// Get a stream of every key event from now on.
+///
+/// Events only arrive while the keyboard task is running.
+pub fn subscribe() -> KeyEvents {
+    KeyEvents {
+        receiver: key_event_sender().subscribe(),
+    }
+}
+
+/// The keyboard task: decode scancodes and send the key events to the
+/// subscribers.
+///
+/// # Panics
+/// Panics if it's started more than once.
+pub async fn run() {
+    let set = if ps2::port_status(Ps2Port::First).is_keyboard() {
+        select_scancode_set()
+    } else {
+        println!("WARNING: no PS/2 keyboard found; plug one in or call `ps2::reset_device`");
+        // A keyboard that is plugged in later starts out with set 2, which
+        // the controller translates.
+        ScancodeSet::Set1
+    };
+
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut decoder = KeyDecoder::new(layout(), set);
+    let mut leds = Leds::new(decoder.locks());
+    let sender = key_event_sender();
 
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+        if leds.handle_reply(scancode) {
+            continue;
+        }
+
+        decoder.set_layout(layout());
+        let event = decoder.add_byte(scancode);
+        leds.update(decoder.locks());
+
+        if let Some(event) = event {
+            if scroll_console(&event) {
+                continue;
+            }
+            // Nobody might be listening, which is fine.
+            let _ = sender.send(event);
+        }
+    }
+}
+
+/// Scroll the VGA console by half a screen for Shift+PageUp and
+/// Shift+PageDown. Returns `true` if `event` was one of them.
+///
+/// Other keys show the live output again, unless they're just modifiers.
+fn scroll_console(event: &KeyEvent) -> bool {
+    let rows = BUFFER_HEIGHT / 2;
+    let scroll_up = match event.code {
+        PhysicalKey::Key(KeyCode::PageUp) if event.modifiers.shift => true,
+        PhysicalKey::Key(KeyCode::PageDown) if event.modifiers.shift => false,
+        _ => {
+            if event.key.is_some() {
+                WRITER.lock().scroll_to_bottom();
             }
+            return false;
+        }
+    };
+
+    // The releases are swallowed too, so nobody sees half of a keystroke.
+    if event.is_press() {
+        let mut writer = WRITER.lock();
+        if scroll_up {
+            writer.scroll_up(rows);
+        } else {
+            writer.scroll_down(rows);
         }
     }
+    true
+}
+
+/// Switch the keyboard to scancode set 2 and turn off the translation to set
+/// 1, returning the set that the scancodes arrive in.
+fn select_scancode_set() -> ScancodeSet {
+    let result = ps2::command(Ps2Port::First, &[SET_SCANCODE_SET, 2])
+        .and_then(|()| ps2::set_translation(false));
+
+    match result {
+        Ok(()) => ScancodeSet::Set2,
+        Err(error) => {
+            println!("WARNING: {}; using scancode set 1", error);
+            // The keyboard is still on set 2, either from the command or
+            // from its reset.
+            if let Err(error) = ps2::set_translation(true) {
+                println!("WARNING: {}; keyboard input may be garbled", error);
+            }
+            ScancodeSet::Set1
+        }
+    }
+}
+
+/// Print any keypresses to the screen.
+pub async fn print_keypresses() {
+    let mut events = subscribe();
+
+    while let Some(event) = events.next().await {
+        if let Some(key) = event.key {
+            print!("{}", key);
+        }
+    }
+}
+
+/// An asynchronous stream of key events, created by `subscribe`.
+///
+/// Events that arrive while the subscriber is more than a few dozen events
+/// behind are skipped.
+#[derive(Debug)]
+pub struct KeyEvents {
+    receiver: broadcast::Receiver<KeyEvent>,
+}
+
+impl KeyEvents {
+    /// Take the next event if there is one, without waiting.
+    pub fn try_next(&mut self) -> Option<KeyEvent> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Stream for KeyEvents {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        loop {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Ok(event)) => return Poll::Ready(Some(event)),
+                Poll::Ready(Err(RecvError::Lagged(_))) => continue,
+                Poll::Ready(Err(RecvError::Closed)) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Keeps the keyboard LEDs in sync with the lock keys.
+///
+/// The keyboard acknowledges the "set LEDs" command before it takes the byte
+/// with the LED bits. The acknowledgement arrives like a scancode, so the
+/// byte is only sent once the keyboard task sees it.
+struct Leds {
+    /// The state the LEDs show or are about to show.
+    locks: LockState,
+    /// Set while we're waiting for the keyboard to acknowledge the command.
+    pending: bool,
+}
+
+impl Leds {
+    fn new(locks: LockState) -> Self {
+        let mut leds = Leds {
+            locks,
+            pending: false,
+        };
+        leds.send_command();
+        leds
+    }
+
+    /// Set the LEDs to `locks` if they changed.
+    fn update(&mut self, locks: LockState) {
+        if locks == self.locks {
+            return;
+        }
+
+        self.locks = locks;
+        // Otherwise the new state is picked up when the ACK arrives.
+        if !self.pending {
+            self.send_command();
+        }
+    }
+
+    /// Handle a reply from the keyboard. Returns `false` if `byte` is a
+    /// scancode instead.
+    fn handle_reply(&mut self, byte: u8) -> bool {
+        match byte {
+            ACK if self.pending => {
+                self.pending = false;
+                write_data(self.locks.led_byte());
+                true
+            }
+            ACK => true,
+            RESEND => {
+                self.send_command();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn send_command(&mut self) {
+        self.pending = true;
+        write_data(SET_LEDS);
+    }
+}
+
+/// Send a byte to the keyboard.
+fn write_data(byte: u8) {
+    if let Err(error) = ps2::send(Ps2Port::First, byte) {
+        println!("WARNING: {}; dropping keyboard byte {:#x}", error, byte);
+    }
 }
 
 /// Called by the keyboard interrupt handler.
@@ -89,3 +369,20 @@ impl Stream for ScancodeStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn typematic_bytes() {
+        // The fastest and the slowest settings.
+        assert_eq!(typematic_byte(250, 30), 0x00);
+        assert_eq!(typematic_byte(1000, 2), 0x7f);
+        // 500ms and 10 repeats a second.
+        assert_eq!(typematic_byte(500, 10), 0x2c);
+        // Out of range values are clamped.
+        assert_eq!(typematic_byte(0, 0), 0x1f);
+        assert_eq!(typematic_byte(u16::MAX, u8::MAX), 0x60);
+    }
+}
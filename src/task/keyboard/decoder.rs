@@ -0,0 +1,461 @@
+//! Turning scancodes into key events, for a layout that can change at
+//! runtime.
+
+use super::scancode::{MediaKey, PhysicalKey, ScancodeDecoder, ScancodeSet};
+use core::fmt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+
+/// A keyboard layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// The standard US layout.
+    Us104,
+    /// The standard UK layout.
+    Uk105,
+    /// The Dvorak layout on a US keyboard.
+    Dvorak104,
+    /// The French AZERTY layout.
+    Azerty,
+}
+
+impl Layout {
+    /// Every supported layout.
+    pub const ALL: [Layout; 4] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::Dvorak104,
+        Layout::Azerty,
+    ];
+
+    /// A short, lowercase name for the layout.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::Dvorak104 => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    /// Look up a layout by its `name`.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+
+    pub(super) fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(super) fn from_u8(value: u8) -> Layout {
+        Self::ALL[value as usize]
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The state of the three lock keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl LockState {
+    /// The PS/2 "set LEDs" command's data byte for this state.
+    pub fn led_byte(self) -> u8 {
+        (self.scroll_lock as u8) | ((self.num_lock as u8) << 1) | ((self.caps_lock as u8) << 2)
+    }
+}
+
+impl Default for LockState {
+    /// Num Lock starts out on, like it does in `pc_keyboard`.
+    fn default() -> Self {
+        LockState {
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+}
+
+/// A decoded keypress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A key that produces a character.
+    Char(char),
+    /// A key that produces a character, pressed while Ctrl was held. The
+    /// character is the one the key produces without Ctrl.
+    Ctrl(char),
+    /// A key that doesn't produce a character.
+    Raw(KeyCode),
+    Media(MediaKey),
+}
+
+impl fmt::Display for Key {
+    /// Characters are written as they are, Ctrl combinations like `^C` and
+    /// other keys by name in angle brackets, like `<Up>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Char(character) => write!(f, "{}", character),
+            Key::Ctrl(character) => write!(f, "^{}", character.to_ascii_uppercase()),
+            Key::Raw(code) => match key_name(*code) {
+                Some(name) => write!(f, "<{}>", name),
+                None => write!(f, "<{:?}>", code),
+            },
+            Key::Media(key) => write!(f, "<{}>", key),
+        }
+    }
+}
+
+/// The usual name of a key that doesn't produce a character, for the keys
+/// whose `KeyCode` doesn't say it well.
+fn key_name(code: KeyCode) -> Option<&'static str> {
+    let name = match code {
+        KeyCode::Escape => "Esc",
+        KeyCode::ArrowUp => "Up",
+        KeyCode::ArrowDown => "Down",
+        KeyCode::ArrowLeft => "Left",
+        KeyCode::ArrowRight => "Right",
+        KeyCode::PageUp => "PgUp",
+        KeyCode::PageDown => "PgDn",
+        KeyCode::Insert => "Ins",
+        KeyCode::Delete => "Del",
+        KeyCode::PrintScreen => "PrtSc",
+        KeyCode::PauseBreak => "Pause",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The modifier keys that were held and the lock keys that were on when a key
+/// event happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    /// Either Shift key.
+    pub shift: bool,
+    /// Either Ctrl key.
+    pub ctrl: bool,
+    /// The left Alt key.
+    pub alt: bool,
+    /// The right Alt key.
+    pub alt_gr: bool,
+    pub locks: LockState,
+}
+
+/// A key being pressed or released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The physical key.
+    pub code: PhysicalKey,
+    pub state: KeyState,
+    /// The modifiers after the event, so pressing Shift reports Shift as held.
+    pub modifiers: Modifiers,
+    /// What the key means in the current layout. Only set for presses.
+    pub key: Option<Key>,
+}
+
+impl KeyEvent {
+    /// Returns `true` if the key was pressed, rather than released.
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+
+    /// The character the key produces, if it was pressed and produces one.
+    /// Ctrl combinations don't count.
+    pub fn char(&self) -> Option<char> {
+        match self.key {
+            Some(Key::Char(character)) => Some(character),
+            _ => None,
+        }
+    }
+}
+
+/// A `pc_keyboard::Keyboard` for any of the layouts, used to map key codes
+/// to what they mean. The scancode set it's created with doesn't matter.
+enum LayoutKeyboard {
+    Us104(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk105(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    Dvorak104(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
+    Azerty(Keyboard<layouts::Azerty, ScancodeSet1>),
+}
+
+impl LayoutKeyboard {
+    fn new(layout: Layout) -> Self {
+        // Ctrl is handled by `KeyDecoder`.
+        let control = HandleControl::Ignore;
+        match layout {
+            Layout::Us104 => {
+                LayoutKeyboard::Us104(Keyboard::new(layouts::Us104Key, ScancodeSet1, control))
+            }
+            Layout::Uk105 => {
+                LayoutKeyboard::Uk105(Keyboard::new(layouts::Uk105Key, ScancodeSet1, control))
+            }
+            Layout::Dvorak104 => LayoutKeyboard::Dvorak104(Keyboard::new(
+                layouts::Dvorak104Key,
+                ScancodeSet1,
+                control,
+            )),
+            Layout::Azerty => {
+                LayoutKeyboard::Azerty(Keyboard::new(layouts::Azerty, ScancodeSet1, control))
+            }
+        }
+    }
+
+    fn process_keyevent(&mut self, event: pc_keyboard::KeyEvent) -> Option<DecodedKey> {
+        match self {
+            LayoutKeyboard::Us104(keyboard) => keyboard.process_keyevent(event),
+            LayoutKeyboard::Uk105(keyboard) => keyboard.process_keyevent(event),
+            LayoutKeyboard::Dvorak104(keyboard) => keyboard.process_keyevent(event),
+            LayoutKeyboard::Azerty(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+
+    /// Press and release `code`, to toggle a lock key.
+    fn toggle(&mut self, code: KeyCode) {
+        self.process_keyevent(pc_keyboard::KeyEvent::new(code, KeyState::Down));
+        self.process_keyevent(pc_keyboard::KeyEvent::new(code, KeyState::Up));
+    }
+}
+
+/// Decodes scancodes into `KeyEvent`s, keeping track of the modifier and
+/// lock keys.
+pub struct KeyDecoder {
+    layout: Layout,
+    scancodes: ScancodeDecoder,
+    keyboard: LayoutKeyboard,
+    locks: LockState,
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+}
+
+impl KeyDecoder {
+    pub fn new(layout: Layout, set: ScancodeSet) -> Self {
+        KeyDecoder {
+            layout,
+            scancodes: ScancodeDecoder::new(set),
+            keyboard: LayoutKeyboard::new(layout),
+            locks: LockState::default(),
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn scancode_set(&self) -> ScancodeSet {
+        self.scancodes.set()
+    }
+
+    /// Switch to another layout, keeping the state of the lock keys.
+    ///
+    /// Shift and AltGr are forgotten, so keys that are held while switching
+    /// count as released.
+    pub fn set_layout(&mut self, layout: Layout) {
+        if layout == self.layout {
+            return;
+        }
+
+        self.layout = layout;
+        self.keyboard = LayoutKeyboard::new(layout);
+
+        let default = LockState::default();
+        if self.locks.caps_lock != default.caps_lock {
+            self.keyboard.toggle(KeyCode::CapsLock);
+        }
+        if self.locks.num_lock != default.num_lock {
+            self.keyboard.toggle(KeyCode::NumpadLock);
+        }
+    }
+
+    pub fn locks(&self) -> LockState {
+        self.locks
+    }
+
+    /// The modifier keys that are held and the lock keys that are on.
+    pub fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.left_shift || self.right_shift,
+            ctrl: self.left_ctrl || self.right_ctrl,
+            alt: self.alt,
+            alt_gr: self.alt_gr,
+            locks: self.locks,
+        }
+    }
+
+    /// Feed a scancode to the decoder. Returns the event it completes, if any.
+    pub fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let (code, state) = self.scancodes.add_byte(scancode)?;
+        let down = state == KeyState::Down;
+
+        match code {
+            PhysicalKey::Key(KeyCode::ShiftLeft) => self.left_shift = down,
+            PhysicalKey::Key(KeyCode::ShiftRight) => self.right_shift = down,
+            PhysicalKey::Key(KeyCode::ControlLeft) => self.left_ctrl = down,
+            PhysicalKey::Key(KeyCode::ControlRight) => self.right_ctrl = down,
+            PhysicalKey::Key(KeyCode::AltLeft) => self.alt = down,
+            PhysicalKey::Key(KeyCode::AltRight) => self.alt_gr = down,
+            PhysicalKey::Key(KeyCode::CapsLock) if down => {
+                self.locks.caps_lock = !self.locks.caps_lock
+            }
+            PhysicalKey::Key(KeyCode::NumpadLock) if down => {
+                self.locks.num_lock = !self.locks.num_lock
+            }
+            PhysicalKey::Key(KeyCode::ScrollLock) if down => {
+                self.locks.scroll_lock = !self.locks.scroll_lock
+            }
+            _ => {}
+        }
+
+        let modifiers = self.modifiers();
+        let key = match code {
+            PhysicalKey::Key(code) => self
+                .keyboard
+                .process_keyevent(pc_keyboard::KeyEvent::new(code, state))
+                .map(|decoded| match decoded {
+                    DecodedKey::Unicode(character) if modifiers.ctrl => Key::Ctrl(character),
+                    DecodedKey::Unicode(character) => Key::Char(character),
+                    DecodedKey::RawKey(code) => Key::Raw(code),
+                }),
+            PhysicalKey::Media(media) if down => Some(Key::Media(media)),
+            PhysicalKey::Media(_) => None,
+        };
+
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers,
+            key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A_DOWN: u8 = 0x1e;
+    const A_UP: u8 = 0x9e;
+    const Q_DOWN: u8 = 0x10;
+    const CAPS_LOCK_DOWN: u8 = 0x3a;
+    const CAPS_LOCK_UP: u8 = 0xba;
+    const LEFT_CTRL_DOWN: u8 = 0x1d;
+    const LEFT_CTRL_UP: u8 = 0x9d;
+    const LEFT_SHIFT_DOWN: u8 = 0x2a;
+    const LEFT_SHIFT_UP: u8 = 0xaa;
+
+    /// Feed `scancode` to `decoder`, returning the decoded key.
+    fn key(decoder: &mut KeyDecoder, scancode: u8) -> Option<Key> {
+        decoder.add_byte(scancode).and_then(|event| event.key)
+    }
+
+    #[test_case]
+    fn caps_lock_toggles() {
+        let mut decoder = KeyDecoder::new(Layout::Us104, ScancodeSet::Set1);
+        assert_eq!(key(&mut decoder, A_DOWN), Some(Key::Char('a')));
+        assert_eq!(key(&mut decoder, A_UP), None);
+
+        decoder.add_byte(CAPS_LOCK_DOWN);
+        decoder.add_byte(CAPS_LOCK_UP);
+        assert!(decoder.locks().caps_lock);
+        assert_eq!(decoder.locks().led_byte(), 0b110);
+        assert_eq!(key(&mut decoder, A_DOWN), Some(Key::Char('A')));
+    }
+
+    #[test_case]
+    fn ctrl_combinations() {
+        let mut decoder = KeyDecoder::new(Layout::Us104, ScancodeSet::Set1);
+        decoder.add_byte(LEFT_CTRL_DOWN);
+        assert_eq!(key(&mut decoder, A_DOWN), Some(Key::Ctrl('a')));
+        decoder.add_byte(A_UP);
+        decoder.add_byte(LEFT_CTRL_UP);
+        assert_eq!(key(&mut decoder, A_DOWN), Some(Key::Char('a')));
+    }
+
+    #[test_case]
+    fn switching_layouts_keeps_locks() {
+        let mut decoder = KeyDecoder::new(Layout::Us104, ScancodeSet::Set1);
+        assert_eq!(key(&mut decoder, Q_DOWN), Some(Key::Char('q')));
+
+        decoder.add_byte(CAPS_LOCK_DOWN);
+        decoder.add_byte(CAPS_LOCK_UP);
+        decoder.set_layout(Layout::Azerty);
+        assert!(decoder.locks().caps_lock);
+        assert_eq!(key(&mut decoder, Q_DOWN), Some(Key::Char('A')));
+    }
+
+    #[test_case]
+    fn events_report_releases_and_modifiers() {
+        let mut decoder = KeyDecoder::new(Layout::Us104, ScancodeSet::Set1);
+
+        let shift = decoder.add_byte(LEFT_SHIFT_DOWN).unwrap();
+        assert_eq!(shift.code, KeyCode::ShiftLeft);
+        assert!(shift.is_press());
+        assert!(shift.modifiers.shift);
+        assert_eq!(shift.key, None);
+
+        let press = decoder.add_byte(A_DOWN).unwrap();
+        assert_eq!(press.code, KeyCode::A);
+        assert_eq!(press.char(), Some('A'));
+        assert!(press.modifiers.shift);
+
+        decoder.add_byte(LEFT_SHIFT_UP);
+        let release = decoder.add_byte(A_UP).unwrap();
+        assert_eq!(release.code, KeyCode::A);
+        assert!(!release.is_press());
+        assert!(!release.modifiers.shift);
+        assert_eq!(release.char(), None);
+    }
+
+    #[test_case]
+    fn scancode_set_2() {
+        let mut decoder = KeyDecoder::new(Layout::Us104, ScancodeSet::Set2);
+        // Left Shift, A, and their releases.
+        decoder.add_byte(0x12);
+        assert_eq!(key(&mut decoder, 0x1c), Some(Key::Char('A')));
+        decoder.add_byte(0xf0);
+        assert_eq!(key(&mut decoder, 0x1c), None);
+        decoder.add_byte(0xf0);
+        decoder.add_byte(0x12);
+        assert!(!decoder.modifiers().shift);
+
+        // Volume Up.
+        decoder.add_byte(0xe0);
+        assert_eq!(
+            key(&mut decoder, 0x32),
+            Some(Key::Media(MediaKey::VolumeUp))
+        );
+    }
+
+    #[test_case]
+    fn key_names() {
+        assert_eq!(key_name(KeyCode::ArrowUp), Some("Up"));
+        assert_eq!(key_name(KeyCode::PauseBreak), Some("Pause"));
+        assert_eq!(key_name(KeyCode::F1), None);
+    }
+
+    #[test_case]
+    fn layout_names() {
+        for &layout in Layout::ALL.iter() {
+            assert_eq!(Layout::from_name(layout.name()), Some(layout));
+        }
+        assert_eq!(Layout::from_name("qwertz"), None);
+    }
+}
@@ -0,0 +1,365 @@
+//! Assembling scancodes of either scancode set into key presses and releases.
+//!
+//! `pc_keyboard` decodes the plain and 0xE0-prefixed scancodes of the keys it
+//! knows about. The sequences it can't handle are picked out before they get
+//! there: Pause's 0xE1 sequence, the fake Shift presses that keyboards wrap
+//! around Print Screen and the navigation keys, and the multimedia keys.
+
+use core::fmt;
+use pc_keyboard::{
+    layouts::Us104Key, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1, ScancodeSet2,
+};
+
+/// The prefix of the scancodes of the extended keys.
+const EXTENDED: u8 = 0xe0;
+/// The first byte of Pause's sequence.
+const PAUSE: u8 = 0xe1;
+/// The prefix of a release in scancode set 2.
+const RELEASE: u8 = 0xf0;
+/// The bit that marks a release in scancode set 1.
+const RELEASE_BIT: u8 = 0x80;
+
+/// The length of Pause's sequence in set 1, `E1 1D 45 E1 9D C5`. Pause has
+/// no release.
+const SET1_PAUSE_LEN: usize = 6;
+/// The length of Pause's sequence in set 2, `E1 14 77 E1 F0 14 F0 77`.
+const SET2_PAUSE_LEN: usize = 8;
+
+/// The scancode set a keyboard sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// The XT set, which the controller translates set 2 into by default.
+    Set1,
+    /// The AT set, which every keyboard supports, if the controller doesn't
+    /// translate it.
+    Set2,
+}
+
+/// A multimedia key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKey {
+    PreviousTrack,
+    NextTrack,
+    Mute,
+    Calculator,
+    PlayPause,
+    Stop,
+    VolumeDown,
+    VolumeUp,
+    WwwHome,
+}
+
+impl MediaKey {
+    /// The multimedia key with the 0xE0-prefixed scancode `code` in `set`.
+    fn from_extended(set: ScancodeSet, code: u8) -> Option<MediaKey> {
+        use ScancodeSet::{Set1, Set2};
+
+        let key = match (set, code) {
+            (Set1, 0x10) | (Set2, 0x15) => MediaKey::PreviousTrack,
+            (Set1, 0x19) | (Set2, 0x4d) => MediaKey::NextTrack,
+            (Set1, 0x20) | (Set2, 0x23) => MediaKey::Mute,
+            (Set1, 0x21) | (Set2, 0x2b) => MediaKey::Calculator,
+            (Set1, 0x22) | (Set2, 0x34) => MediaKey::PlayPause,
+            (Set1, 0x24) | (Set2, 0x3b) => MediaKey::Stop,
+            (Set1, 0x2e) | (Set2, 0x21) => MediaKey::VolumeDown,
+            (Set1, 0x30) | (Set2, 0x32) => MediaKey::VolumeUp,
+            (Set1, 0x32) | (Set2, 0x3a) => MediaKey::WwwHome,
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MediaKey::PreviousTrack => "Previous Track",
+            MediaKey::NextTrack => "Next Track",
+            MediaKey::Mute => "Mute",
+            MediaKey::Calculator => "Calculator",
+            MediaKey::PlayPause => "Play/Pause",
+            MediaKey::Stop => "Stop",
+            MediaKey::VolumeDown => "Volume Down",
+            MediaKey::VolumeUp => "Volume Up",
+            MediaKey::WwwHome => "WWW Home",
+        }
+    }
+}
+
+impl fmt::Display for MediaKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A physical key on the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicalKey {
+    /// One of the keys of a standard keyboard.
+    Key(KeyCode),
+    Media(MediaKey),
+}
+
+impl PartialEq<KeyCode> for PhysicalKey {
+    fn eq(&self, other: &KeyCode) -> bool {
+        *self == PhysicalKey::Key(*other)
+    }
+}
+
+/// How far a sequence of scancodes got.
+#[derive(Debug, PartialEq, Eq)]
+enum Sequence {
+    /// It needs more bytes.
+    Incomplete,
+    /// Pause was pressed.
+    Pause,
+    /// The extended key with the given scancode, without the prefix or the
+    /// release marker, changed its state.
+    Extended(u8, KeyState),
+    /// A sequence that `pc_keyboard` can decode.
+    Other,
+}
+
+impl Sequence {
+    fn of(set: ScancodeSet, bytes: &[u8]) -> Sequence {
+        use ScancodeSet::{Set1, Set2};
+
+        match (set, bytes) {
+            (Set1, [PAUSE, ..]) if bytes.len() < SET1_PAUSE_LEN => Sequence::Incomplete,
+            (Set2, [PAUSE, ..]) if bytes.len() < SET2_PAUSE_LEN => Sequence::Incomplete,
+            (_, [PAUSE, ..]) => Sequence::Pause,
+            (_, [EXTENDED]) | (Set2, [RELEASE]) | (Set2, [EXTENDED, RELEASE]) => {
+                Sequence::Incomplete
+            }
+            (Set1, &[EXTENDED, code]) if code & RELEASE_BIT != 0 => {
+                Sequence::Extended(code & !RELEASE_BIT, KeyState::Up)
+            }
+            (Set1, &[EXTENDED, code]) => Sequence::Extended(code, KeyState::Down),
+            (Set2, &[EXTENDED, code]) => Sequence::Extended(code, KeyState::Down),
+            (Set2, &[EXTENDED, RELEASE, code]) => Sequence::Extended(code, KeyState::Up),
+            _ => Sequence::Other,
+        }
+    }
+}
+
+/// Returns `true` if the 0xE0-prefixed `code` is one of the Shift presses and
+/// releases that keyboards send around some extended keys, to undo the
+/// effect of a held Shift or Num Lock on them.
+fn is_fake_shift(set: ScancodeSet, code: u8) -> bool {
+    match set {
+        ScancodeSet::Set1 => code == 0x2a || code == 0x36,
+        ScancodeSet::Set2 => code == 0x12 || code == 0x59,
+    }
+}
+
+/// Returns `true` if the 0xE0-prefixed `code` is Print Screen.
+fn is_print_screen(set: ScancodeSet, code: u8) -> bool {
+    match set {
+        ScancodeSet::Set1 => code == 0x37,
+        ScancodeSet::Set2 => code == 0x7c,
+    }
+}
+
+/// A `pc_keyboard::Keyboard` for either scancode set, only used to turn
+/// scancodes into key codes. The layout doesn't matter for that.
+enum SetKeyboard {
+    Set1(Keyboard<Us104Key, ScancodeSet1>),
+    Set2(Keyboard<Us104Key, ScancodeSet2>),
+}
+
+impl SetKeyboard {
+    fn new(set: ScancodeSet) -> Self {
+        let control = HandleControl::Ignore;
+        match set {
+            ScancodeSet::Set1 => SetKeyboard::Set1(Keyboard::new(Us104Key, ScancodeSet1, control)),
+            ScancodeSet::Set2 => SetKeyboard::Set2(Keyboard::new(Us104Key, ScancodeSet2, control)),
+        }
+    }
+
+    /// Feed `bytes` to the keyboard, returning the event the last one
+    /// completes.
+    fn add_bytes(&mut self, bytes: &[u8]) -> Option<(PhysicalKey, KeyState)> {
+        let mut event = None;
+        for &byte in bytes {
+            let result = match self {
+                SetKeyboard::Set1(keyboard) => keyboard.add_byte(byte),
+                SetKeyboard::Set2(keyboard) => keyboard.add_byte(byte),
+            };
+            event = result.ok().flatten();
+        }
+        event.map(|event| (PhysicalKey::Key(event.code), event.state))
+    }
+}
+
+/// Turns the scancodes of a set into key presses and releases.
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    keyboard: SetKeyboard,
+    /// The bytes of the unfinished sequence.
+    pending: [u8; SET2_PAUSE_LEN],
+    len: usize,
+}
+
+impl ScancodeDecoder {
+    pub fn new(set: ScancodeSet) -> Self {
+        ScancodeDecoder {
+            set,
+            keyboard: SetKeyboard::new(set),
+            pending: [0; SET2_PAUSE_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Feed a scancode to the decoder. Returns the key and its new state if
+    /// the scancode completes a sequence.
+    ///
+    /// Pause is only ever reported as pressed.
+    pub fn add_byte(&mut self, scancode: u8) -> Option<(PhysicalKey, KeyState)> {
+        self.pending[self.len] = scancode;
+        self.len += 1;
+        let pending = self.pending;
+        let bytes = &pending[..self.len];
+
+        let event = match Sequence::of(self.set, bytes) {
+            Sequence::Incomplete => return None,
+            Sequence::Pause => Some((PhysicalKey::Key(KeyCode::PauseBreak), KeyState::Down)),
+            Sequence::Extended(code, _) if is_fake_shift(self.set, code) => None,
+            Sequence::Extended(code, state) if is_print_screen(self.set, code) => {
+                Some((PhysicalKey::Key(KeyCode::PrintScreen), state))
+            }
+            Sequence::Extended(code, state) => match MediaKey::from_extended(self.set, code) {
+                Some(key) => Some((PhysicalKey::Media(key), state)),
+                None => self.keyboard.add_bytes(bytes),
+            },
+            Sequence::Other => self.keyboard.add_bytes(bytes),
+        };
+        self.len = 0;
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `scancodes` to `decoder`, returning the events in order.
+    fn events(
+        decoder: &mut ScancodeDecoder,
+        scancodes: &[u8],
+    ) -> [Option<(PhysicalKey, KeyState)>; 2] {
+        let mut events = [None; 2];
+        let mut len = 0;
+        for &scancode in scancodes {
+            if let Some(event) = decoder.add_byte(scancode) {
+                events[len] = Some(event);
+                len += 1;
+            }
+        }
+        events
+    }
+
+    fn key(code: KeyCode, state: KeyState) -> Option<(PhysicalKey, KeyState)> {
+        Some((PhysicalKey::Key(code), state))
+    }
+
+    #[test_case]
+    fn set_2_releases() {
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+        assert_eq!(
+            events(&mut decoder, &[0x1c, 0xf0, 0x1c]),
+            [
+                key(KeyCode::A, KeyState::Down),
+                key(KeyCode::A, KeyState::Up)
+            ]
+        );
+    }
+
+    #[test_case]
+    fn extended_keys() {
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set1);
+        assert_eq!(
+            events(&mut decoder, &[0xe0, 0x48, 0xe0, 0xc8]),
+            [
+                key(KeyCode::ArrowUp, KeyState::Down),
+                key(KeyCode::ArrowUp, KeyState::Up)
+            ]
+        );
+
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+        assert_eq!(
+            events(&mut decoder, &[0xe0, 0x75, 0xe0, 0xf0, 0x75]),
+            [
+                key(KeyCode::ArrowUp, KeyState::Down),
+                key(KeyCode::ArrowUp, KeyState::Up)
+            ]
+        );
+    }
+
+    #[test_case]
+    fn print_screen_without_fake_shift() {
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set1);
+        assert_eq!(
+            events(
+                &mut decoder,
+                &[0xe0, 0x2a, 0xe0, 0x37, 0xe0, 0xb7, 0xe0, 0xaa]
+            ),
+            [
+                key(KeyCode::PrintScreen, KeyState::Down),
+                key(KeyCode::PrintScreen, KeyState::Up)
+            ]
+        );
+
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+        let scancodes = [0xe0, 0x12, 0xe0, 0x7c, 0xe0, 0xf0, 0x7c, 0xe0, 0xf0, 0x12];
+        assert_eq!(
+            events(&mut decoder, &scancodes),
+            [
+                key(KeyCode::PrintScreen, KeyState::Down),
+                key(KeyCode::PrintScreen, KeyState::Up)
+            ]
+        );
+    }
+
+    #[test_case]
+    fn pause() {
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set1);
+        assert_eq!(
+            events(&mut decoder, &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x1e]),
+            [
+                key(KeyCode::PauseBreak, KeyState::Down),
+                key(KeyCode::A, KeyState::Down)
+            ]
+        );
+
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+        let scancodes = [0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77, 0x1c];
+        assert_eq!(
+            events(&mut decoder, &scancodes),
+            [
+                key(KeyCode::PauseBreak, KeyState::Down),
+                key(KeyCode::A, KeyState::Down)
+            ]
+        );
+    }
+
+    #[test_case]
+    fn media_keys() {
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+        assert_eq!(
+            events(&mut decoder, &[0xe0, 0x32, 0xe0, 0xf0, 0x32]),
+            [
+                Some((PhysicalKey::Media(MediaKey::VolumeUp), KeyState::Down)),
+                Some((PhysicalKey::Media(MediaKey::VolumeUp), KeyState::Up))
+            ]
+        );
+
+        let mut decoder = ScancodeDecoder::new(ScancodeSet::Set1);
+        assert_eq!(decoder.add_byte(0xe0), None);
+        assert_eq!(
+            decoder.add_byte(0xa0),
+            Some((PhysicalKey::Media(MediaKey::Mute), KeyState::Up))
+        );
+    }
+}
@@ -1,18 +1,44 @@
+mod builder;
+pub mod channel;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod mouse;
+pub mod readline;
+mod run_queue;
+pub mod serial;
 pub mod simple_executor;
+mod stats;
+pub mod sync;
 
-use alloc::boxed::Box;
+pub use builder::Builder;
+pub use executor::Spawner;
+pub use join::{AbortHandle, JoinError, JoinHandle};
+pub use stats::{TaskSnapshot, WakeSource};
+
+use crate::lock::IrqSafeMutex;
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use conquer_once::spin::OnceCell;
 use core::{
+    fmt,
     future::Future,
+    mem::ManuallyDrop,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
-    task::{Context, Poll},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
+use executor::Worker;
+use stats::TaskStats;
 
 /// The ID of a `Task`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -20,34 +46,309 @@ impl TaskId {
 
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// The ID as a plain number.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The scheduling class of a task.
+///
+/// The executor always prefers tasks in a higher class, but every class is
+/// guaranteed at least one poll per scheduling round so that nothing starves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Bottom halves of interrupt handlers, like the keyboard task. These
+    /// should do a little bit of work and go back to sleep quickly.
+    Interrupt = 0,
+    /// Regular tasks.
+    Normal = 1,
+    /// Long-running work that should only use up spare time.
+    Background = 2,
+}
+
+impl Priority {
+    /// The number of priority classes.
+    pub const COUNT: usize = 3;
+
+    /// Every priority class, highest first.
+    pub const ALL: [Priority; Priority::COUNT] =
+        [Priority::Interrupt, Priority::Normal, Priority::Background];
+
+    /// Convert the `Priority` to an index into per-class arrays.
+    #[inline(always)]
+    pub fn as_usize(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// The part of a `Task` that is shared with its wakers and the executors'
+/// run queues.
+pub(crate) struct TaskHeader {
+    id: TaskId,
+    /// A human-readable name for diagnostics.
+    name: Option<String>,
+    priority: Priority,
+    /// The task's future, until it completes or gets cancelled.
+    ///
+    /// Never locked in interrupt handlers. Whoever polls the task holds the
+    /// lock for the duration of the poll.
+    future: spin::Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// The waker handed to the future, created the first time it's polled.
+    waker: spin::Mutex<Option<Waker>>,
+    /// Set while the task is waiting in a run queue, so that waking it again
+    /// doesn't queue it twice.
+    scheduled: AtomicBool,
+    /// The next task in the intrusive `RunQueue` this task is queued in.
+    next: AtomicPtr<TaskHeader>,
+    /// The executor the task was spawned on. Set once the task is spawned.
+    home: OnceCell<Arc<Worker>>,
+    /// The executor whose run queue the task gets woken into: `home`, or the
+    /// executor that most recently stole the task. Null until the task is
+    /// spawned.
+    ///
+    /// Only ever points at `home`, which we keep alive, or at the worker of an
+    /// executor that is running, which never goes away.
+    worker: AtomicPtr<Worker>,
+    /// Set once the task has been aborted.
+    aborted: AtomicBool,
+    /// Tasks spawned while this task was being polled. They get aborted along
+    /// with this task.
+    children: IrqSafeMutex<Vec<Weak<TaskHeader>>>,
+    /// Runtime statistics, updated by the executor and the task's wakers.
+    stats: TaskStats,
+}
+
+impl TaskHeader {
+    fn new(id: TaskId, name: Option<String>, priority: Priority) -> Self {
+        TaskHeader {
+            id,
+            name,
+            priority,
+            future: spin::Mutex::new(None),
+            waker: spin::Mutex::new(None),
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            home: OnceCell::uninit(),
+            worker: AtomicPtr::new(ptr::null_mut()),
+            aborted: AtomicBool::new(false),
+            children: IrqSafeMutex::new(Vec::new()),
+            stats: TaskStats::new(),
+        }
+    }
+
+    /// Put the task in the run queue of the executor it belongs to, unless
+    /// it's already there. Does nothing if the task hasn't been spawned yet.
+    fn wake(self: &Arc<Self>) {
+        // See `worker` for why the executor is still alive.
+        let worker = unsafe { self.worker.load(Ordering::Acquire).as_ref() };
+
+        if let Some(worker) = worker {
+            if self.schedule() {
+                worker.push_woken(self.clone());
+            }
+        }
+    }
+
+    /// Drop the task's future and its cached waker. If the future hasn't
+    /// completed yet, this cancels the task.
+    fn release(&self) {
+        let future = self.future.lock().take();
+        let waker = self.waker.lock().take();
+        drop((future, waker));
+    }
+
+    /// Returns `true` if the task has been aborted.
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    /// Abort the task and all of its children.
+    ///
+    /// The task gets woken so that the executor notices and drops it.
+    fn abort(self: &Arc<Self>) {
+        if self.aborted.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        self.wake();
+
+        let children = core::mem::take(&mut *self.children.lock());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.abort();
+        }
+    }
+
+    /// Register `child` as a child of this task, so that aborting this task
+    /// aborts `child` too.
+    fn add_child(&self, child: &Arc<TaskHeader>) {
+        {
+            let mut children = self.children.lock();
+            // Forget about children that are long gone.
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(child));
+        }
+
+        // Handle the parent having been aborted while we weren't looking.
+        if self.is_aborted() {
+            child.abort();
+        }
+    }
+
+    /// Mark the task as scheduled. Returns `true` if it wasn't already, in
+    /// which case the caller must put it in a run queue.
+    fn schedule(&self) -> bool {
+        !self.scheduled.swap(true, Ordering::AcqRel)
+    }
+
+    /// Clear the scheduled flag right before the task is polled, so that any
+    /// wakeups during or after the poll queue it again.
+    fn unschedule(&self) {
+        self.scheduled.store(false, Ordering::Release);
+    }
 }
 
 /// A wrapper around a pinned, heap allocated and dynamically dispatched future
 /// with the empty type `()` as output.
 ///
-/// - Since the task returns `()`, tasks can't return any result. They are just
-///   executed for their side effects.
+/// - Since the task returns `()`, the task itself can't return any result.
+///   Futures with other outputs are wrapped by `Task::joinable`, which hands
+///   their output to a `JoinHandle` instead.
 /// - The future is `dyn` so that we can use any function that returns `()` as
 ///   a task.
+/// - The future must be `Send` so that tasks can be handed to the executor
+///   through a `Spawner` from anywhere in the kernel, and stolen by the
+///   executors of other CPUs.
 /// - The `Pin<Box<...>>` ensures that the future cannot be moved in memory,
 ///   which is good because futures generated by `async`/`.await` might be
 ///   self-referential.
+///
+/// The future lives in the task's header, so that the executor of any CPU can
+/// poll it. Dropping a `Task` that was never spawned cancels it.
 pub struct Task {
-    id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    header: Arc<TaskHeader>,
 }
 
 impl Task {
     /// Create a new task.
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task {
-            id: TaskId::new(),
-            future: Box::pin(future),
-        }
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        let header = TaskHeader::new(TaskId::new(), None, Priority::default());
+        Task::with_header(Arc::new(header), future)
+    }
+
+    /// Create a task that runs `future` under the given header.
+    fn with_header(
+        header: Arc<TaskHeader>,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> Task {
+        *header.future.lock() = Some(Box::pin(future));
+        Task { header }
+    }
+
+    /// The task's ID.
+    pub fn id(&self) -> TaskId {
+        self.header.id
+    }
+
+    /// Create a new task from a future with any output type, along with a
+    /// `JoinHandle` that can be awaited to get that output.
+    ///
+    /// Use a `Builder` to configure the task first.
+    pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        Builder::new().build(future)
+    }
+
+    /// Get a handle that can be used to abort this task.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle::new(self.header.clone())
     }
 
     /// Poll the stored future.
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let mut future = self.header.future.lock();
+
+        match future.as_mut() {
+            Some(future) => future.as_mut().poll(context),
+            None => Poll::Ready(()),
+        }
+    }
+
+    /// Hand the task's header over to an executor, which becomes responsible
+    /// for eventually releasing the future.
+    fn into_header(self) -> Arc<TaskHeader> {
+        let task = ManuallyDrop::new(self);
+        unsafe { ptr::read(&task.header) }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.header.release();
+    }
+}
+
+/// Spawn a future on this CPU's executor, returning a `JoinHandle` for its
+/// output.
+///
+/// This is a shortcut for `executor::spawner().spawn(future)`, meant to be
+/// used from inside tasks.
+///
+/// # Panics
+/// Panics if no `Executor` has been started with `Executor::run` yet.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    executor::spawner()
+        .expect("task::spawn called before the executor was started")
+        .spawn(future)
+}
+
+/// Yield to the executor, letting other ready tasks run before this one
+/// continues.
+///
+/// Long-running tasks should call this every once in a while.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// The future returned by `yield_now`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            // Waking ourselves puts us at the back of our priority class's
+            // queue.
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
     }
 }
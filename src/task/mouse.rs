@@ -0,0 +1,329 @@
+//! The PS/2 mouse on the controller's second port.
+//!
+//! The interrupt handler queues the raw bytes, and `MouseStream` turns them
+//! into a `MouseEvent` per packet. Creating the stream switches a mouse that
+//! supports it into IntelliMouse mode, where it reports a scroll wheel and up
+//! to five buttons in 4-byte packets, and then turns on data reporting.
+
+use crate::{
+    interrupts::{self, InterruptIndex},
+    println,
+    ps2::{self, DeviceType, Ps2Error, Ps2Port},
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// Mouse commands.
+const SET_DEFAULTS: u8 = 0xf6;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_DATA_REPORTING: u8 = 0xf4;
+
+// Bits of the first byte of a packet.
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+/// Always set, which helps finding the start of a packet.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+// Bits of the fourth byte of a five button mouse's packet.
+const FOURTH_BUTTON: u8 = 1 << 4;
+const FIFTH_BUTTON: u8 = 1 << 5;
+
+/// Called by the mouse interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            println!("WARNING: mouse queue full; dropping mouse input");
+        } else {
+            WAKER.wake();
+        }
+    }
+    // Without a stream, data reporting is off, so this is a stray reply.
+}
+
+/// The buttons of a mouse, and whether they're pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    /// Usually the "back" button, only on five button mice.
+    pub fourth: bool,
+    /// Usually the "forward" button, only on five button mice.
+    pub fifth: bool,
+}
+
+/// What happened since the mouse's last packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    /// Horizontal movement. Positive is to the right.
+    pub dx: i16,
+    /// Vertical movement. Positive is up.
+    pub dy: i16,
+    /// Scroll wheel movement. Positive is towards the user.
+    pub scroll: i8,
+    /// The buttons that are pressed now.
+    pub buttons: MouseButtons,
+}
+
+/// The packet format of a mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketFormat {
+    /// Three bytes: buttons, X and Y.
+    Standard,
+    /// Four bytes, the last one for the scroll wheel.
+    ScrollWheel,
+    /// Four bytes, the last one for the scroll wheel and two more buttons.
+    FiveButtons,
+}
+
+impl PacketFormat {
+    fn for_device(device: DeviceType) -> Self {
+        match device {
+            DeviceType::ScrollMouse => PacketFormat::ScrollWheel,
+            DeviceType::FiveButtonMouse => PacketFormat::FiveButtons,
+            _ => PacketFormat::Standard,
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            PacketFormat::Standard => 3,
+            PacketFormat::ScrollWheel | PacketFormat::FiveButtons => 4,
+        }
+    }
+}
+
+/// Assembles mouse packets from single bytes.
+#[derive(Debug)]
+pub struct PacketDecoder {
+    format: PacketFormat,
+    packet: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub fn new(format: PacketFormat) -> Self {
+        PacketDecoder {
+            format,
+            packet: [0; 4],
+            len: 0,
+        }
+    }
+
+    /// Feed a byte to the decoder. Returns the event of the packet it
+    /// completes, if any.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // If a byte got lost, wait for something that looks like the start of
+        // a packet.
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.format.len() {
+            return None;
+        }
+
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let [flags, x, y, extra] = self.packet;
+
+        let mut event = MouseEvent {
+            buttons: MouseButtons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+                fourth: false,
+                fifth: false,
+            },
+            ..MouseEvent::default()
+        };
+
+        // The movement is a 9-bit two's complement number, with the sign in
+        // the first byte. It's useless if it overflowed.
+        if flags & (X_OVERFLOW | Y_OVERFLOW) == 0 {
+            event.dx = i16::from(x) - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+            event.dy = i16::from(y) - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+        }
+
+        match self.format {
+            PacketFormat::Standard => {}
+            PacketFormat::ScrollWheel => event.scroll = extra as i8,
+            PacketFormat::FiveButtons => {
+                // The scroll wheel gets a signed nibble.
+                event.scroll = ((extra << 4) as i8) >> 4;
+                event.buttons.fourth = extra & FOURTH_BUTTON != 0;
+                event.buttons.fifth = extra & FIFTH_BUTTON != 0;
+            }
+        }
+
+        event
+    }
+}
+
+/// Set up the mouse and return the packet format it uses.
+///
+/// Setting the sample rate to 200, 100 and 80 is the magic sequence that
+/// makes a scroll mouse identify as one. After that, 200, 200 and 80 does
+/// the same for five button mice.
+fn init_mouse() -> Result<PacketFormat, Ps2Error> {
+    let port = Ps2Port::Second;
+
+    ps2::command(port, &[SET_DEFAULTS])?;
+    set_sample_rates(&[200, 100, 80])?;
+    let mut device = ps2::identify(port)?;
+    if device == DeviceType::ScrollMouse {
+        set_sample_rates(&[200, 200, 80])?;
+        device = ps2::identify(port)?;
+    }
+    if !device.is_mouse() {
+        return Err(Ps2Error::WrongDevice(device));
+    }
+
+    // Go back to a sensible rate.
+    set_sample_rates(&[100])?;
+    ps2::command(port, &[ENABLE_DATA_REPORTING])?;
+    Ok(PacketFormat::for_device(device))
+}
+
+/// Set the mouse's sample rate to each of `rates` in turn.
+fn set_sample_rates(rates: &[u8]) -> Result<(), Ps2Error> {
+    rates
+        .iter()
+        .try_for_each(|&rate| ps2::command(Ps2Port::Second, &[SET_SAMPLE_RATE, rate]))
+}
+
+/// An asynchronous stream of mouse events.
+pub struct MouseStream {
+    decoder: PacketDecoder,
+}
+
+impl MouseStream {
+    /// Set up the mouse and start receiving its events.
+    ///
+    /// # Panics
+    /// Panics if it's called more than once.
+    pub fn new() -> Result<Self, Ps2Error> {
+        // If this fails, it can be tried again once a mouse is plugged in.
+        let format = init_mouse()?;
+
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(128))
+            .expect("MouseStream::new should only be called once");
+        interrupts::unmask(InterruptIndex::Mouse);
+
+        Ok(MouseStream {
+            decoder: PacketDecoder::new(format),
+        })
+    }
+
+    /// The packet format of the mouse.
+    pub fn format(&self) -> PacketFormat {
+        self.decoder.format
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+
+        loop {
+            let byte = match queue.pop() {
+                Some(byte) => byte,
+                None => {
+                    WAKER.register(cx.waker());
+                    match queue.pop() {
+                        Some(byte) => {
+                            WAKER.take();
+                            byte
+                        }
+                        None => return Poll::Pending,
+                    }
+                }
+            };
+
+            if let Some(event) = self.decoder.add_byte(byte) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn standard_packet() {
+        let mut decoder = PacketDecoder::new(PacketFormat::Standard);
+        assert_eq!(decoder.add_byte(ALWAYS_ONE | LEFT_BUTTON | X_SIGN), None);
+        assert_eq!(decoder.add_byte(0xfe), None);
+        let event = decoder.add_byte(5).unwrap();
+
+        assert_eq!(event.dx, -2);
+        assert_eq!(event.dy, 5);
+        assert_eq!(event.scroll, 0);
+        assert!(event.buttons.left);
+        assert!(!event.buttons.right);
+    }
+
+    #[test_case]
+    fn overflow_drops_movement() {
+        let mut decoder = PacketDecoder::new(PacketFormat::Standard);
+        decoder.add_byte(ALWAYS_ONE | X_OVERFLOW | RIGHT_BUTTON);
+        decoder.add_byte(0x10);
+        let event = decoder.add_byte(0x10).unwrap();
+
+        assert_eq!((event.dx, event.dy), (0, 0));
+        assert!(event.buttons.right);
+    }
+
+    #[test_case]
+    fn resynchronizes() {
+        let mut decoder = PacketDecoder::new(PacketFormat::ScrollWheel);
+        // Not the start of a packet.
+        assert_eq!(decoder.add_byte(0x00), None);
+
+        decoder.add_byte(ALWAYS_ONE);
+        decoder.add_byte(0);
+        decoder.add_byte(0);
+        let event = decoder.add_byte(0xff).unwrap();
+        assert_eq!(event.scroll, -1);
+    }
+
+    #[test_case]
+    fn five_buttons() {
+        let mut decoder = PacketDecoder::new(PacketFormat::FiveButtons);
+        decoder.add_byte(ALWAYS_ONE | MIDDLE_BUTTON | Y_SIGN);
+        decoder.add_byte(0);
+        decoder.add_byte(0xff);
+        let event = decoder.add_byte(FIFTH_BUTTON | 0x0e).unwrap();
+
+        assert_eq!(event.dy, -1);
+        assert_eq!(event.scroll, -2);
+        assert!(event.buttons.middle);
+        assert!(event.buttons.fifth);
+        assert!(!event.buttons.fourth);
+    }
+}
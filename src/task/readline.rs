@@ -0,0 +1,448 @@
+//! Reading lines from the keyboard with line editing and a history.
+//!
+//! `Readline::read_line` shows a prompt on the last row of the VGA text
+//! buffer and lets the user edit the line until Enter is pressed:
+//!
+//! - Backspace and Delete remove the character before and under the cursor.
+//! - The left and right arrow keys move the cursor, Home and End move it to
+//!   the start and the end of the line.
+//! - Ctrl-U removes everything before the cursor, Ctrl-W the word before it.
+//! - Up and Down go through the lines entered before.
+//! - Tab completes the word before the cursor with the suggestions of the
+//!   completer set with `Readline::set_completer`.
+//!
+//! The line has to fit into the row, so input that doesn't is ignored.
+
+use crate::{
+    print, println,
+    task::keyboard::{self, Key, KeyCode, KeyEvents},
+    vga_buffer::{BUFFER_WIDTH, WRITER},
+};
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
+use futures_util::stream::StreamExt;
+
+/// The longest line that can be entered.
+const MAX_LEN: usize = BUFFER_WIDTH - 1;
+
+/// How many lines the history keeps.
+const HISTORY_LEN: usize = 32;
+
+/// Suggests completions for the last word of a line, see
+/// `Readline::set_completer`.
+type Completer = Box<dyn FnMut(&str) -> Vec<String> + Send>;
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+
+/// The characters of the line being edited, and the cursor.
+struct LineBuffer {
+    chars: [char; MAX_LEN],
+    len: usize,
+    cursor: usize,
+    /// How many characters fit behind the prompt.
+    capacity: usize,
+}
+
+impl LineBuffer {
+    fn new(capacity: usize) -> Self {
+        LineBuffer {
+            chars: ['\0'; MAX_LEN],
+            len: 0,
+            cursor: 0,
+            capacity: capacity.min(MAX_LEN),
+        }
+    }
+
+    /// Change how many characters fit behind the prompt, dropping the ones
+    /// that don't anymore.
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.min(MAX_LEN);
+        self.len = self.len.min(self.capacity);
+        self.cursor = self.cursor.min(self.len);
+    }
+
+    fn chars(&self) -> &[char] {
+        &self.chars[..self.len]
+    }
+
+    /// Insert `character` at the cursor. Returns `false` if the line is full.
+    fn insert(&mut self, character: char) -> bool {
+        if self.len == self.capacity {
+            return false;
+        }
+
+        self.chars
+            .copy_within(self.cursor..self.len, self.cursor + 1);
+        self.chars[self.cursor] = character;
+        self.len += 1;
+        self.cursor += 1;
+        true
+    }
+
+    /// Insert as much of `text` at the cursor as fits.
+    fn insert_str(&mut self, text: &str) {
+        for character in text.chars() {
+            if !self.insert(character) {
+                break;
+            }
+        }
+    }
+
+    /// Remove the characters from `start` up to the cursor.
+    fn remove_before_cursor(&mut self, start: usize) {
+        self.chars.copy_within(self.cursor..self.len, start);
+        self.len -= self.cursor - start;
+        self.cursor = start;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.remove_before_cursor(self.cursor - 1);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.len {
+            self.cursor += 1;
+            self.backspace();
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.len);
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.len;
+    }
+
+    /// Ctrl-U: remove everything before the cursor.
+    fn kill_to_start(&mut self) {
+        self.remove_before_cursor(0);
+    }
+
+    /// Ctrl-W: remove the word before the cursor.
+    fn kill_word(&mut self) {
+        let start = self.chars[..self.cursor]
+            .iter()
+            .rposition(|character| !character.is_whitespace())
+            .map_or(0, |end| self.word_start_before(end + 1));
+        self.remove_before_cursor(start);
+    }
+
+    /// Where the word that ends at `end` starts.
+    fn word_start_before(&self, end: usize) -> usize {
+        self.chars[..end]
+            .iter()
+            .rposition(|character| character.is_whitespace())
+            .map_or(0, |space| space + 1)
+    }
+
+    /// Where the word before the cursor starts. It's empty if the cursor
+    /// follows a space.
+    fn word_start(&self) -> usize {
+        self.word_start_before(self.cursor)
+    }
+
+    /// Replace the line with `text`, with the cursor at the end.
+    fn set(&mut self, text: &str) {
+        self.len = 0;
+        self.cursor = 0;
+        self.insert_str(text);
+    }
+
+    fn text(&self) -> String {
+        self.chars().iter().collect()
+    }
+}
+
+/// Draw `line` behind the prompt that ends at column `start` of the last row
+/// and put the writer's position at the cursor.
+fn render(line: &LineBuffer, start: usize) {
+    let mut writer = WRITER.lock();
+    writer.set_column_position(start);
+    for character in line.chars() {
+        writer.write_string(character.encode_utf8(&mut [0; 4]));
+    }
+    writer.clear_rest_of_row();
+    writer.set_column_position(start + line.cursor);
+}
+
+/// Print `prompt`, returning the column the line starts at.
+fn print_prompt(prompt: &str) -> usize {
+    // Make sure there's room for the line.
+    let column = WRITER.lock().column_position();
+    if column + prompt.chars().count() > MAX_LEN / 2 {
+        println!();
+    }
+    print!("{}", prompt);
+    WRITER.lock().column_position()
+}
+
+/// The longest string that every string in `strings` starts with.
+fn common_prefix(strings: &[String]) -> &str {
+    let first = match strings.first() {
+        Some(first) => first.as_str(),
+        None => return "",
+    };
+
+    strings[1..].iter().fold(first, |prefix, string| {
+        let len = prefix
+            .char_indices()
+            .zip(string.chars())
+            .find(|&((_, a), b)| a != b)
+            .map_or(prefix.len().min(string.len()), |((index, _), _)| index);
+        &prefix[..len]
+    })
+}
+
+/// Reads lines from the keyboard, with line editing and a history.
+pub struct Readline {
+    events: KeyEvents,
+    /// The lines entered before, oldest first.
+    history: VecDeque<String>,
+    completer: Option<Completer>,
+}
+
+impl Readline {
+    /// Start listening to the keyboard. Keys that are pressed before the
+    /// first `read_line` are part of the first line.
+    pub fn new() -> Self {
+        Readline {
+            events: keyboard::subscribe(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            completer: None,
+        }
+    }
+
+    /// Complete words with `completer` when Tab is pressed.
+    ///
+    /// It gets the line up to the cursor and returns the words the last word
+    /// of it could be completed to, which is empty if it's at a space.
+    pub fn set_completer(&mut self, completer: impl FnMut(&str) -> Vec<String> + Send + 'static) {
+        self.completer = Some(Box::new(completer));
+    }
+
+    /// The lines entered before, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Show `prompt` and read a line. Returns `None` if the keyboard task is
+    /// gone.
+    pub async fn read_line(&mut self, prompt: &str) -> Option<String> {
+        let mut start = print_prompt(prompt);
+        let mut line = LineBuffer::new(MAX_LEN.saturating_sub(start));
+        // The entry of the history that is shown, and the line that was
+        // edited before going through the history.
+        let mut history_index: Option<usize> = None;
+        let mut draft = String::new();
+
+        while let Some(event) = self.events.next().await {
+            let key = match event.key {
+                Some(key) => key,
+                None => continue,
+            };
+
+            match key {
+                Key::Char('\n') => {
+                    println!();
+                    let line = line.text();
+                    self.add_to_history(&line);
+                    return Some(line);
+                }
+                Key::Char('\t') => {
+                    if self.complete(&mut line) {
+                        start = print_prompt(prompt);
+                        line.set_capacity(MAX_LEN.saturating_sub(start));
+                    }
+                }
+                Key::Char(BACKSPACE) => line.backspace(),
+                Key::Char(DELETE) => line.delete(),
+                Key::Char(character) if !character.is_control() => {
+                    line.insert(character);
+                }
+                Key::Ctrl('u') => line.kill_to_start(),
+                Key::Ctrl('w') => line.kill_word(),
+                Key::Raw(KeyCode::ArrowLeft) => line.left(),
+                Key::Raw(KeyCode::ArrowRight) => line.right(),
+                Key::Raw(KeyCode::Home) => line.home(),
+                Key::Raw(KeyCode::End) => line.end(),
+                Key::Raw(KeyCode::ArrowUp) => {
+                    let index = match history_index {
+                        None if self.history.is_empty() => continue,
+                        None => {
+                            draft = line.text();
+                            self.history.len() - 1
+                        }
+                        Some(index) => index.saturating_sub(1),
+                    };
+                    history_index = Some(index);
+                    line.set(&self.history[index]);
+                }
+                Key::Raw(KeyCode::ArrowDown) => match history_index {
+                    None => continue,
+                    Some(index) if index + 1 < self.history.len() => {
+                        history_index = Some(index + 1);
+                        line.set(&self.history[index + 1]);
+                    }
+                    Some(_) => {
+                        history_index = None;
+                        line.set(&draft);
+                    }
+                },
+                _ => continue,
+            }
+
+            render(&line, start);
+        }
+
+        None
+    }
+
+    /// Complete the word before the cursor. Returns `true` if the
+    /// suggestions were listed and the prompt has to be printed again.
+    fn complete(&mut self, line: &mut LineBuffer) -> bool {
+        let completer = match &mut self.completer {
+            Some(completer) => completer,
+            None => return false,
+        };
+
+        let before_cursor: String = line.chars()[..line.cursor].iter().collect();
+        let word_start: usize = line.chars()[..line.word_start()]
+            .iter()
+            .map(|character| character.len_utf8())
+            .sum();
+        let word = &before_cursor[word_start..];
+
+        let mut candidates = completer(&before_cursor);
+        candidates.retain(|candidate| candidate.starts_with(word));
+
+        match candidates.len() {
+            0 => false,
+            1 => {
+                line.insert_str(&candidates[0][word.len()..]);
+                line.insert(' ');
+                false
+            }
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() > word.len() {
+                    line.insert_str(&prefix[word.len()..]);
+                    return false;
+                }
+
+                println!();
+                for candidate in &candidates {
+                    print!("{}  ", candidate);
+                }
+                println!();
+                true
+            }
+        }
+    }
+
+    fn add_to_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().map(String::as_str) == Some(line) {
+            return;
+        }
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.into());
+    }
+}
+
+impl Default for Readline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_with(text: &str) -> LineBuffer {
+        let mut line = LineBuffer::new(MAX_LEN);
+        line.insert_str(text);
+        line
+    }
+
+    fn assert_line(line: &LineBuffer, text: &str, cursor: usize) {
+        assert!(line.chars().iter().copied().eq(text.chars()));
+        assert_eq!(line.cursor, cursor);
+    }
+
+    #[test_case]
+    fn editing() {
+        let mut line = line_with("helo");
+        line.left();
+        line.insert('l');
+        assert_line(&line, "hello", 4);
+
+        line.home();
+        line.delete();
+        line.end();
+        line.backspace();
+        assert_line(&line, "ell", 3);
+
+        line.right();
+        line.backspace();
+        line.backspace();
+        line.backspace();
+        line.backspace();
+        assert_line(&line, "", 0);
+    }
+
+    #[test_case]
+    fn killing() {
+        let mut line = line_with("echo hello  world");
+        line.kill_word();
+        assert_line(&line, "echo hello  ", 12);
+        line.kill_word();
+        assert_line(&line, "echo ", 5);
+
+        let mut line = line_with("echo hello");
+        line.left();
+        line.kill_to_start();
+        assert_line(&line, "o", 0);
+    }
+
+    #[test_case]
+    fn full_line() {
+        let mut line = LineBuffer::new(3);
+        line.insert_str("abcd");
+        assert_line(&line, "abc", 3);
+        line.home();
+        assert!(!line.insert('x'));
+        assert_line(&line, "abc", 0);
+    }
+
+    #[test_case]
+    fn changing_capacity() {
+        let mut line = line_with("abcdef");
+        line.set_capacity(4);
+        assert_line(&line, "abcd", 4);
+        line.set_capacity(5);
+        line.insert_str("xyz");
+        assert_line(&line, "abcdx", 5);
+    }
+
+    #[test_case]
+    fn word_start() {
+        let mut line = line_with("pagewalk 0x1");
+        assert_eq!(line.word_start(), 9);
+        line.insert(' ');
+        assert_eq!(line.word_start(), 13);
+    }
+}
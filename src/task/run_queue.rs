@@ -0,0 +1,110 @@
+use super::TaskHeader;
+use alloc::sync::Arc;
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// An unbounded queue of tasks that are ready to be polled.
+///
+/// The queue is an intrusive, lock-free stack threaded through the `next`
+/// pointers of the queued `TaskHeader`s. Pushing never allocates and never
+/// blocks, so it's safe to wake tasks from interrupt handlers. The executor
+/// takes the whole stack at once with `take_all`, which hands the tasks back
+/// in the order they were pushed.
+///
+/// A header must never be in the queue twice, since it only has one `next`
+/// pointer. `TaskHeader::schedule` makes sure of that.
+pub(crate) struct RunQueue {
+    head: AtomicPtr<TaskHeader>,
+}
+
+impl RunQueue {
+    /// Create an empty run queue.
+    pub(crate) fn new() -> Self {
+        RunQueue {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Push a task onto the queue.
+    ///
+    /// Must only be called for tasks that aren't already in the queue.
+    pub(crate) fn push(&self, task: Arc<TaskHeader>) {
+        let node = Arc::into_raw(task) as *mut TaskHeader;
+        let mut head = self.head.load(Ordering::Relaxed);
+
+        loop {
+            // The node isn't in the queue yet, so nobody else touches its
+            // `next` pointer.
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(new_head) => head = new_head,
+            }
+        }
+    }
+
+    /// Returns `true` if there are no tasks in the queue.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Take every task out of the queue, oldest first.
+    pub(crate) fn take_all(&self) -> TakeAll {
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+
+        // The stack gives us the newest task first, so reverse it. We own all
+        // of these nodes now.
+        let mut reversed = ptr::null_mut();
+        while !node.is_null() {
+            unsafe {
+                let next = (*node).next.load(Ordering::Relaxed);
+                (*node).next.store(reversed, Ordering::Relaxed);
+                reversed = node;
+                node = next;
+            }
+        }
+
+        TakeAll { next: reversed }
+    }
+}
+
+impl Drop for RunQueue {
+    fn drop(&mut self) {
+        // Release our references to any tasks still in the queue.
+        for _ in self.take_all() {}
+    }
+}
+
+/// An iterator over the tasks taken out of a `RunQueue` by `take_all`.
+pub(crate) struct TakeAll {
+    next: *mut TaskHeader,
+}
+
+impl Iterator for TakeAll {
+    type Item = Arc<TaskHeader>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let node = self.next;
+            self.next = (*node).next.load(Ordering::Relaxed);
+            Some(Arc::from_raw(node))
+        }
+    }
+}
+
+impl Drop for TakeAll {
+    fn drop(&mut self) {
+        // Don't leak the references of any tasks the caller didn't look at.
+        for _ in self {}
+    }
+}
@@ -0,0 +1,219 @@
+//! Input from the first serial port.
+//!
+//! The interrupt handler queues the received bytes, and `SerialInput` hands
+//! them out as a stream or assembles them into lines, echoing them back like
+//! a terminal in cooked mode would.
+
+use crate::{
+    interrupts::{self, InterruptIndex},
+    println, serial, serial_print,
+};
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const ESCAPE: u8 = 0x1b;
+/// Ctrl-U.
+const KILL_LINE: u8 = 0x15;
+
+/// Called by the serial interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            println!("WARNING: serial input queue full; dropping input");
+        } else {
+            WAKER.wake();
+        }
+    }
+    // Without a `SerialInput`, nobody is interested in the input.
+}
+
+/// Where `InputFilter` is in an escape sequence it's skipping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// After an `ESC`.
+    Start,
+    /// In a control sequence, after `ESC [`. It ends with a byte from `@` to
+    /// `~`.
+    Csi,
+    /// After `ESC O`, which the keypad and function keys send. One more byte
+    /// follows.
+    Ss3,
+}
+
+/// Drops the bytes that `read_line` doesn't handle: escape sequences, and
+/// the `\n` of a `\r\n` line ending.
+#[derive(Debug)]
+struct InputFilter {
+    escape: Escape,
+    /// Set if the last byte was a `\r`.
+    after_cr: bool,
+}
+
+impl InputFilter {
+    const fn new() -> Self {
+        InputFilter {
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    /// Returns `byte` if `read_line` should handle it.
+    fn filter(&mut self, byte: u8) -> Option<u8> {
+        let after_cr = self.after_cr;
+        self.after_cr = byte == b'\r';
+
+        match (self.escape, byte) {
+            // An escape always starts a new sequence.
+            (_, ESCAPE) => self.escape = Escape::Start,
+            (Escape::None, b'\n') if after_cr => {}
+            (Escape::None, byte) => return Some(byte),
+            (Escape::Start, b'[') => self.escape = Escape::Csi,
+            (Escape::Start, b'O') => self.escape = Escape::Ss3,
+            // Other escape sequences are two bytes long, like `ESC c`.
+            (Escape::Start, _) | (Escape::Ss3, _) => self.escape = Escape::None,
+            (Escape::Csi, b'@'..=b'~') => self.escape = Escape::None,
+            (Escape::Csi, _) => {}
+        }
+        None
+    }
+}
+
+/// An asynchronous stream of the bytes received on the first serial port.
+pub struct SerialInput {
+    filter: InputFilter,
+}
+
+impl SerialInput {
+    /// Start receiving bytes.
+    ///
+    /// # Panics
+    /// Panics if it's called more than once.
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(128))
+            .expect("SerialInput::new should only be called once");
+        // Initializing the port turns on its receive interrupt.
+        lazy_static::initialize(&serial::SERIAL1);
+        interrupts::unmask(InterruptIndex::Serial);
+
+        SerialInput {
+            filter: InputFilter::new(),
+        }
+    }
+
+    /// Read a line, echoing what's typed. Backspace and Ctrl-U work, escape
+    /// sequences like the arrow keys are ignored. Lines can end with `\r`,
+    /// `\n` or `\r\n`. Returns `None` if the stream ends.
+    pub async fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+
+        while let Some(byte) = self.next().await {
+            let byte = match self.filter.filter(byte) {
+                Some(byte) => byte,
+                None => continue,
+            };
+
+            match byte {
+                b'\r' | b'\n' => {
+                    serial_print!("\r\n");
+                    return Some(line);
+                }
+                BACKSPACE | DELETE => {
+                    if line.pop().is_some() {
+                        serial_print!("\x08 \x08");
+                    }
+                }
+                KILL_LINE => {
+                    for _ in line.drain(..) {
+                        serial_print!("\x08 \x08");
+                    }
+                }
+                0x20..=0x7e => {
+                    line.push(char::from(byte));
+                    serial_print!("{}", char::from(byte));
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for SerialInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialInput {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `input` through a new filter, returning how many bytes came out
+    /// and the bytes themselves.
+    fn filter(input: &[u8]) -> (usize, [u8; 8]) {
+        let mut filter = InputFilter::new();
+        let mut output = [0; 8];
+        let mut len = 0;
+        for &byte in input {
+            if let Some(byte) = filter.filter(byte) {
+                output[len] = byte;
+                len += 1;
+            }
+        }
+        (len, output)
+    }
+
+    #[test_case]
+    fn line_endings() {
+        assert_eq!(filter(b"a\r\nb"), (3, *b"a\rb\0\0\0\0\0"));
+        assert_eq!(filter(b"a\n\nb"), (4, *b"a\n\nb\0\0\0\0"));
+        assert_eq!(filter(b"\r\r\n"), (2, *b"\r\r\0\0\0\0\0\0"));
+    }
+
+    #[test_case]
+    fn escape_sequences() {
+        // An arrow key, F1 and Shift+F5.
+        assert_eq!(filter(b"a\x1b[Ab\x1bOPc"), (3, *b"abc\0\0\0\0\0"));
+        assert_eq!(filter(b"\x1b[15;2~d\x1bce"), (2, *b"de\0\0\0\0\0\0"));
+    }
+}
@@ -41,6 +41,10 @@ impl SimpleExecutor {
     /// Run the simple executor in a hilariously inefficient manner.
     pub fn run(&mut self) {
         while let Some(mut task) = self.task_queue.pop_front() {
+            if task.header.is_aborted() {
+                continue; // dropping the task cancels it
+            }
+
             let waker = dummy_waker();
             let mut context = Context::from_waker(&waker);
 
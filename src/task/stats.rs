@@ -0,0 +1,169 @@
+use super::{Priority, TaskHeader, TaskId};
+use alloc::string::String;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Read the CPU's time stamp counter.
+pub(crate) fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// What woke a task up most recently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeSource {
+    /// The task was just spawned.
+    Spawn,
+    /// A hardware interrupt handler woke the task.
+    Interrupt,
+    /// A task woke it (possibly itself).
+    Task(TaskId),
+    /// Something else, like the executor itself, woke the task.
+    Other,
+}
+
+impl WakeSource {
+    // `WakeSource`s are packed into a `u64` so they fit in an atomic. Task IDs
+    // are stored with an offset of `TASK_BASE`.
+    const NONE: u64 = 0;
+    const SPAWN: u64 = 1;
+    const INTERRUPT: u64 = 2;
+    const OTHER: u64 = 3;
+    const TASK_BASE: u64 = 4;
+
+    fn encode(self) -> u64 {
+        match self {
+            WakeSource::Spawn => Self::SPAWN,
+            WakeSource::Interrupt => Self::INTERRUPT,
+            WakeSource::Other => Self::OTHER,
+            WakeSource::Task(id) => Self::TASK_BASE + id.as_u64(),
+        }
+    }
+
+    fn decode(raw: u64) -> Option<Self> {
+        match raw {
+            Self::NONE => None,
+            Self::SPAWN => Some(WakeSource::Spawn),
+            Self::INTERRUPT => Some(WakeSource::Interrupt),
+            Self::OTHER => Some(WakeSource::Other),
+            id => Some(WakeSource::Task(TaskId(id - Self::TASK_BASE))),
+        }
+    }
+}
+
+impl fmt::Display for WakeSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WakeSource::Spawn => write!(f, "spawn"),
+            WakeSource::Interrupt => write!(f, "interrupt"),
+            WakeSource::Task(id) => write!(f, "task {}", id),
+            WakeSource::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Runtime statistics of a single task, updated by the executor.
+///
+/// Times are measured in timer ticks (see `interrupts::ticks`), durations in
+/// CPU cycles.
+pub(crate) struct TaskStats {
+    spawned_at: AtomicU64,
+    polls: AtomicU64,
+    poll_cycles: AtomicU64,
+    last_polled_at: AtomicU64,
+    last_wake: AtomicU64,
+    last_woken_at: AtomicU64,
+}
+
+impl TaskStats {
+    pub(crate) fn new() -> Self {
+        TaskStats {
+            spawned_at: AtomicU64::new(0),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            last_polled_at: AtomicU64::new(0),
+            last_wake: AtomicU64::new(WakeSource::NONE),
+            last_woken_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that the task was handed to an executor.
+    pub(crate) fn record_spawn(&self) {
+        self.spawned_at
+            .store(crate::interrupts::ticks(), Ordering::Relaxed);
+        self.record_wake(WakeSource::Spawn);
+    }
+
+    /// Record a single poll that took `cycles` CPU cycles.
+    pub(crate) fn record_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.last_polled_at
+            .store(crate::interrupts::ticks(), Ordering::Relaxed);
+    }
+
+    /// Record that the task was woken by `source`.
+    pub(crate) fn record_wake(&self, source: WakeSource) {
+        self.last_wake.store(source.encode(), Ordering::Relaxed);
+        self.last_woken_at
+            .store(crate::interrupts::ticks(), Ordering::Relaxed);
+    }
+}
+
+/// A point-in-time copy of a task's metadata and statistics, as returned by
+/// `Spawner::snapshot`.
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub priority: Priority,
+    /// The tick the task was spawned at.
+    pub spawned_at: u64,
+    /// How many times the task has been polled.
+    pub polls: u64,
+    /// The total number of CPU cycles spent polling the task.
+    pub poll_cycles: u64,
+    /// The tick the task was last polled at, if it has been polled at all.
+    pub last_polled_at: Option<u64>,
+    /// What woke the task up last, and at which tick.
+    pub last_wake: Option<(WakeSource, u64)>,
+    /// Whether the task is currently waiting in a run queue.
+    pub scheduled: bool,
+    /// Whether the task has been aborted.
+    pub aborted: bool,
+}
+
+impl TaskSnapshot {
+    /// The average number of CPU cycles per poll.
+    pub fn average_poll_cycles(&self) -> u64 {
+        self.poll_cycles.checked_div(self.polls).unwrap_or(0)
+    }
+}
+
+impl TaskHeader {
+    /// Take a snapshot of the task's metadata and statistics.
+    pub(crate) fn snapshot(&self) -> TaskSnapshot {
+        let stats = &self.stats;
+        let polls = stats.polls.load(Ordering::Relaxed);
+        let last_wake = WakeSource::decode(stats.last_wake.load(Ordering::Relaxed))
+            .map(|source| (source, stats.last_woken_at.load(Ordering::Relaxed)));
+
+        TaskSnapshot {
+            id: self.id,
+            name: self.name.clone(),
+            priority: self.priority,
+            spawned_at: stats.spawned_at.load(Ordering::Relaxed),
+            polls,
+            poll_cycles: stats.poll_cycles.load(Ordering::Relaxed),
+            last_polled_at: if polls > 0 {
+                Some(stats.last_polled_at.load(Ordering::Relaxed))
+            } else {
+                None
+            },
+            last_wake,
+            scheduled: self.scheduled.load(Ordering::Acquire),
+            aborted: self.is_aborted(),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use super::wait_list::WaitList;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Lets a fixed number of tasks wait for each other to reach the same point.
+///
+/// Once the last of them calls `wait`, all of them continue and the barrier
+/// resets so it can be used again.
+pub struct Barrier {
+    num_tasks: usize,
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    /// The number of tasks waiting in the current generation.
+    arrived: usize,
+    /// Bumped every time the barrier releases its waiters.
+    generation: u64,
+    waiters: WaitList<()>,
+}
+
+impl Barrier {
+    /// Create a barrier that releases tasks in groups of `num_tasks`.
+    pub fn new(num_tasks: usize) -> Self {
+        Barrier {
+            num_tasks,
+            state: spin::Mutex::new(State {
+                arrived: 0,
+                generation: 0,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// Wait until `num_tasks` tasks are waiting on the barrier.
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            waiting: None,
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Barrier")
+            .field("num_tasks", &self.num_tasks)
+            .field("arrived", &self.state.lock().arrived)
+            .finish()
+    }
+}
+
+/// Returned to every task released by a `Barrier`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Returns `true` for exactly one of the tasks released together: the one
+    /// that arrived last.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+/// The future returned by `Barrier::wait`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    /// The generation we're waiting in and our key in the wait list, once
+    /// we've arrived.
+    waiting: Option<(u64, u64)>,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let barrier = self.barrier;
+        let mut state = barrier.state.lock();
+
+        match self.waiting {
+            None => {
+                state.arrived += 1;
+
+                if state.arrived >= barrier.num_tasks {
+                    // We're the last one; release everybody.
+                    state.arrived = 0;
+                    state.generation += 1;
+
+                    for entry in state.waiters.iter_mut() {
+                        entry.waker.wake_by_ref();
+                    }
+
+                    return Poll::Ready(BarrierWaitResult { is_leader: true });
+                }
+
+                let key = state.waiters.push_back(cx.waker(), ());
+                self.waiting = Some((state.generation, key));
+                Poll::Pending
+            }
+            Some((generation, key)) => {
+                if state.generation != generation {
+                    state.waiters.remove(key);
+                    self.waiting = None;
+                    return Poll::Ready(BarrierWaitResult { is_leader: false });
+                }
+
+                state.waiters.update(key, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for BarrierWait<'_> {
+    fn drop(&mut self) {
+        if let Some((generation, key)) = self.waiting {
+            let mut state = self.barrier.state.lock();
+            state.waiters.remove(key);
+
+            // A task that gives up waiting no longer counts as arrived.
+            if state.generation == generation {
+                state.arrived -= 1;
+            }
+        }
+    }
+}
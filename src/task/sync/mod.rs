@@ -0,0 +1,19 @@
+//! Async synchronization primitives.
+//!
+//! These work like their `spin` counterparts, except that waiting parks the
+//! current task's `Waker` and lets the executor run something else until the
+//! task is woken up again. They're meant to be used from inside tasks, not
+//! from interrupt handlers.
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+mod wait_list;
+
+pub use barrier::{Barrier, BarrierWait, BarrierWaitResult};
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
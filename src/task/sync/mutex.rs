@@ -0,0 +1,102 @@
+use super::{Semaphore, SemaphorePermit};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+/// An async mutual exclusion lock.
+///
+/// Unlike `spin::Mutex`, waiting for the lock parks the current task instead
+/// of spinning, so other tasks get to run in the meantime. Waiters get the
+/// lock in the order they asked for it.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Create a new, unlocked mutex.
+    pub fn new(data: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the mutex, returning the data inside.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Wait until the lock is free, then take it.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        MutexGuard::new(self, permit)
+    }
+
+    /// Take the lock if it's free right now.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire()?;
+        Some(MutexGuard::new(self, permit))
+    }
+
+    /// Get a mutable reference to the data. No locking is needed since the
+    /// `&mut self` guarantees that nobody else has access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// Holds a `Mutex` locked. The lock is released when this is dropped.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>, permit: SemaphorePermit<'a>) -> Self {
+        MutexGuard {
+            mutex,
+            _permit: permit,
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Holding the permit means we're the only one with access.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,160 @@
+use super::wait_list::WaitList;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Wakes up tasks waiting for an event.
+///
+/// `notify_one` wakes a single waiting task. If no task is waiting, the
+/// notification is stored and the next call to `notified` completes right
+/// away, so a notification sent just before a task starts waiting isn't lost.
+/// `notify_waiters` wakes every task that's currently waiting, but isn't
+/// stored.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    /// Set if `notify_one` was called with nobody waiting.
+    permit: bool,
+    waiters: WaitList<Notification>,
+}
+
+/// Whether a waiting task has been notified, and how.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    Waiting,
+    One,
+    All,
+}
+
+impl State {
+    /// Notify the oldest waiting task, or store a permit if there is none.
+    fn notify_one(&mut self) {
+        let waiter = self
+            .waiters
+            .iter_mut()
+            .find(|entry| entry.data == Notification::Waiting);
+
+        match waiter {
+            Some(entry) => {
+                entry.data = Notification::One;
+                entry.waker.wake_by_ref();
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+impl Notify {
+    /// Create a new `Notify` with no stored notification.
+    pub fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// Wait for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            key: None,
+        }
+    }
+
+    /// Wake the task that has been waiting the longest. If nobody is waiting,
+    /// the next call to `notified` completes immediately instead.
+    pub fn notify_one(&self) {
+        self.state.lock().notify_one();
+    }
+
+    /// Wake every task that is currently waiting.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+
+        for entry in state.waiters.iter_mut() {
+            if entry.data == Notification::Waiting {
+                entry.data = Notification::All;
+                entry.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Notify")
+            .field("permit", &self.state.lock().permit)
+            .finish()
+    }
+}
+
+/// The future returned by `Notify::notified`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Our key in the wait list, if we're in it.
+    key: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+
+        match self.key {
+            None => {
+                if state.permit {
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+
+                let key = state.waiters.push_back(cx.waker(), Notification::Waiting);
+                drop(state);
+                self.key = Some(key);
+                Poll::Pending
+            }
+            Some(key) => {
+                let notification = *state
+                    .waiters
+                    .update(key, cx.waker())
+                    .expect("notify waiter vanished");
+
+                if notification == Notification::Waiting {
+                    return Poll::Pending;
+                }
+
+                state.waiters.remove(key);
+                drop(state);
+                self.key = None;
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            let mut state = self.notify.state.lock();
+
+            // Don't swallow a `notify_one` meant for us; pass it on instead.
+            if state.waiters.remove(key) == Some(Notification::One) {
+                state.notify_one();
+            }
+        }
+    }
+}
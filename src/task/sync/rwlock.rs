@@ -0,0 +1,146 @@
+use super::{Semaphore, SemaphorePermit};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+/// The maximum number of readers that can hold an `RwLock` at once. A writer
+/// takes all of them.
+const MAX_READERS: usize = usize::MAX >> 3;
+
+/// An async reader-writer lock.
+///
+/// Any number of readers or a single writer can hold the lock at once. Waiters
+/// are served in FIFO order, so a steady stream of readers can't starve a
+/// writer.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Create a new, unlocked `RwLock`.
+    pub fn new(data: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the lock, returning the data inside.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Wait until no writer holds the lock, then take shared read access.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    /// Take shared read access if no writer holds or waits for the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire()?;
+        Some(RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    /// Wait until nobody else holds the lock, then take exclusive write
+    /// access.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READERS).await;
+        RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    /// Take exclusive write access if nobody else holds the lock right now.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire_many(MAX_READERS)?;
+        Some(RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    /// Get a mutable reference to the data. No locking is needed since the
+    /// `&mut self` guarantees that nobody else has access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("RwLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// Shared read access to an `RwLock`, released when this is dropped.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // No writer can exist while we hold a read permit.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Exclusive write access to an `RwLock`, released when this is dropped.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Holding every permit means we're the only one with access.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
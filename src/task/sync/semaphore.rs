@@ -0,0 +1,220 @@
+use super::wait_list::WaitList;
+use core::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// An async counting semaphore.
+///
+/// Permits are handed out in FIFO order: once a task is waiting, later
+/// callers queue up behind it even if there'd be enough permits for them, so
+/// that tasks asking for many permits at once don't starve.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: WaitList<Waiter>,
+}
+
+/// A task waiting for permits.
+struct Waiter {
+    /// How many permits the task asked for.
+    needed: usize,
+    /// Set once the permits have been taken out of the pool on the task's
+    /// behalf.
+    granted: bool,
+}
+
+impl State {
+    /// Returns `true` if any task is still waiting for permits.
+    fn has_waiters(&self) -> bool {
+        self.waiters.data().any(|waiter| !waiter.granted)
+    }
+
+    /// Hand out permits to waiting tasks, in order, for as long as there are
+    /// enough left for the next one.
+    fn grant(&mut self) {
+        for entry in self.waiters.iter_mut() {
+            if entry.data.granted {
+                continue;
+            }
+            if entry.data.needed > self.permits {
+                break;
+            }
+
+            self.permits -= entry.data.needed;
+            entry.data.granted = true;
+            entry.waker.wake_by_ref();
+        }
+    }
+}
+
+impl Semaphore {
+    /// Create a new semaphore with the given number of permits.
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// The number of permits that are currently available.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Add `n` permits to the semaphore, waking tasks that were waiting on
+    /// them.
+    pub fn add_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.permits += n;
+        state.grant();
+    }
+
+    /// Wait for a single permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Wait for `n` permits at once.
+    pub fn acquire_many(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: n,
+            key: None,
+        }
+    }
+
+    /// Take a single permit if one is available right now.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Take `n` permits if they're available right now and nobody else is
+    /// waiting for permits.
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+
+        if !state.has_waiters() && state.permits >= n {
+            state.permits -= n;
+            Some(SemaphorePermit::new(self, n))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .finish()
+    }
+}
+
+/// Permits taken from a `Semaphore`. They're given back when this is dropped.
+#[must_use = "the permits are released as soon as this is dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    fn new(semaphore: &'a Semaphore, permits: usize) -> Self {
+        SemaphorePermit { semaphore, permits }
+    }
+
+    /// Keep the permits out of the semaphore for good.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+/// The future returned by `Semaphore::acquire` and `Semaphore::acquire_many`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    /// Our key in the semaphore's wait list, if we're in it.
+    key: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+        let mut state = semaphore.state.lock();
+
+        match self.key {
+            None => {
+                if !state.has_waiters() && state.permits >= needed {
+                    state.permits -= needed;
+                    return Poll::Ready(SemaphorePermit::new(semaphore, needed));
+                }
+
+                let waiter = Waiter {
+                    needed,
+                    granted: false,
+                };
+                self.key = Some(state.waiters.push_back(cx.waker(), waiter));
+                Poll::Pending
+            }
+            Some(key) => {
+                let granted = state
+                    .waiters
+                    .update(key, cx.waker())
+                    .expect("semaphore waiter vanished")
+                    .granted;
+
+                if granted {
+                    state.waiters.remove(key);
+                    self.key = None;
+                    Poll::Ready(SemaphorePermit::new(semaphore, needed))
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            let mut state = self.semaphore.state.lock();
+
+            if let Some(waiter) = state.waiters.remove(key) {
+                if waiter.granted {
+                    // We got the permits but never picked them up.
+                    state.permits += waiter.needed;
+                }
+            }
+
+            // We might have been the one holding up the line.
+            state.grant();
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use alloc::collections::VecDeque;
+use core::task::Waker;
+
+/// A FIFO list of parked tasks.
+///
+/// Every entry gets a key when it's inserted, so that the future that parked
+/// it can find it again later to refresh its waker, check on it or remove it
+/// when it gets dropped. Each entry also carries some data for the
+/// synchronization primitive that owns the list.
+pub(super) struct WaitList<T> {
+    entries: VecDeque<Entry<T>>,
+    next_key: u64,
+}
+
+/// A single parked task.
+pub(super) struct Entry<T> {
+    key: u64,
+    pub(super) waker: Waker,
+    pub(super) data: T,
+}
+
+impl<T> WaitList<T> {
+    /// Create an empty list.
+    pub(super) fn new() -> Self {
+        WaitList {
+            entries: VecDeque::new(),
+            next_key: 0,
+        }
+    }
+
+    /// Park a task at the back of the list, returning its key.
+    pub(super) fn push_back(&mut self, waker: &Waker, data: T) -> u64 {
+        let key = self.next_key;
+        self.next_key += 1;
+
+        self.entries.push_back(Entry {
+            key,
+            waker: waker.clone(),
+            data,
+        });
+
+        key
+    }
+
+    /// Get the entry with the given key.
+    pub(super) fn get_mut(&mut self, key: u64) -> Option<&mut Entry<T>> {
+        self.entries.iter_mut().find(|entry| entry.key == key)
+    }
+
+    /// Refresh the waker of the entry with the given key, returning its data.
+    pub(super) fn update(&mut self, key: u64, waker: &Waker) -> Option<&mut T> {
+        let entry = self.get_mut(key)?;
+
+        if !entry.waker.will_wake(waker) {
+            entry.waker = waker.clone();
+        }
+
+        Some(&mut entry.data)
+    }
+
+    /// Remove the entry with the given key, returning its data.
+    pub(super) fn remove(&mut self, key: u64) -> Option<T> {
+        let index = self.entries.iter().position(|entry| entry.key == key)?;
+        self.entries.remove(index).map(|entry| entry.data)
+    }
+
+    /// Iterate over the entries, oldest first.
+    pub(super) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Entry<T>> {
+        self.entries.iter_mut()
+    }
+
+    /// Iterate over the data of the entries, oldest first.
+    pub(super) fn data(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().map(|entry| &entry.data)
+    }
+}
@@ -0,0 +1,94 @@
+use alloc::{boxed::Box, vec};
+use core::mem;
+
+/// The size of a kernel thread's stack.
+///
+/// There's no guard page below the stack, so overflowing it silently corrupts
+/// the heap. Keep threads away from deep recursion and big stack buffers.
+pub(super) const STACK_SIZE: usize = 4096 * 4;
+
+// Save the callee-saved registers on the current stack, store the stack
+// pointer in `*old_rsp` (`rdi`), load `new_rsp` (`rsi`) and restore the
+// registers saved on that stack. Returns into whatever the new stack was
+// switched away from, or into the entry point of a fresh thread.
+//
+// The caller-saved registers are already on the stack because this is a
+// regular function call, and the kernel doesn't use SSE, so there's no
+// floating point state to save either.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+.att_syntax prefix
+"#
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut usize, new_rsp: usize);
+}
+
+/// The number of registers `switch_context` saves on the stack.
+const SAVED_REGISTERS: usize = 6;
+
+/// Save the current register context to `old_rsp` and switch to the context
+/// saved at `new_rsp`.
+///
+/// # Safety
+/// Interrupts must be disabled, and `new_rsp` must have been saved by this
+/// function or created by `Stack::initial_rsp`. Whatever the stack at
+/// `new_rsp` belongs to must stay alive until it's switched away from again.
+pub(super) unsafe fn switch(old_rsp: *mut usize, new_rsp: usize) {
+    switch_context(old_rsp, new_rsp);
+}
+
+/// A heap allocated kernel thread stack.
+pub(super) struct Stack {
+    memory: Box<[u8]>,
+}
+
+impl Stack {
+    pub(super) fn new() -> Self {
+        Stack {
+            memory: vec![0; STACK_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Prepare the stack so that switching to it enters `entry`, returning
+    /// the stack pointer to switch to.
+    pub(super) fn initial_rsp(&mut self, entry: extern "C" fn() -> !) -> usize {
+        let word = mem::size_of::<usize>();
+        let end = self.memory.as_mut_ptr() as usize + self.memory.len();
+        // The System V ABI wants the stack 16-byte aligned right before a
+        // call, so `entry` should see it 8 bytes off after the "call".
+        let top = end & !0xf;
+
+        // From the top: a fake return address for `entry`, the address
+        // `switch_context` returns to, and the registers it restores.
+        let rsp = top - (SAVED_REGISTERS + 2) * word;
+        unsafe {
+            let frame = rsp as *mut usize;
+            for i in 0..SAVED_REGISTERS {
+                frame.add(i).write(0);
+            }
+            frame.add(SAVED_REGISTERS).write(entry as usize);
+            frame.add(SAVED_REGISTERS + 1).write(0);
+        }
+        rsp
+    }
+}
@@ -0,0 +1,165 @@
+//! Preemptive kernel threads.
+//!
+//! Each thread gets its own stack and is scheduled round-robin. Threads are
+//! preempted on every timer interrupt, so unlike an async task, a thread that
+//! loops without yielding can't freeze the rest of the kernel.
+//!
+//! The code that calls `spawn_thread` first becomes the boot thread, which
+//! keeps running on the bootloader's stack.
+//!
+//! There's a single scheduler, and threads only ever run on the BSP. The APs
+//! just run executors, so the functions here panic if a task running on an AP
+//! calls them.
+
+mod context;
+mod scheduler;
+
+pub(crate) use scheduler::preempt;
+
+use crate::percpu::{self, PerCpu};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use scheduler::{scheduler, Thread};
+use x86_64::instructions::interrupts;
+
+/// The ID of a kernel thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The ID as a plain number.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Start a new kernel thread running `f`, returning a `JoinHandle` for its
+/// result.
+///
+/// The thread gets its first turn once the current thread yields, blocks or
+/// gets preempted.
+pub fn spawn_thread<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // Create the scheduler first, so that the boot thread gets the lowest ID.
+    let scheduler = scheduler();
+    let result = Arc::new(spin::Mutex::new(None));
+
+    let result_clone = result.clone();
+    let thread = Thread::spawn(Box::new(move || {
+        let value = f();
+        *result_clone.lock() = Some(value);
+    }));
+
+    let handle = JoinHandle {
+        thread: thread.clone(),
+        result,
+    };
+    interrupts::without_interrupts(|| scheduler.lock().add(thread));
+    handle
+}
+
+/// Get the ID of the thread running on this CPU.
+///
+/// Returns `None` until the first thread is spawned.
+pub fn current_id() -> Option<ThreadId> {
+    match percpu::current().current_thread.load(Ordering::Relaxed) {
+        PerCpu::NO_THREAD => None,
+        id => Some(ThreadId(id)),
+    }
+}
+
+/// Give the rest of the current thread's time slice to the next ready thread.
+///
+/// Does nothing if no other thread is ready.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| scheduler::switch(scheduler().lock(), true));
+}
+
+/// Block the current thread for at least `ticks` timer ticks (see
+/// `interrupts::ticks`).
+pub fn sleep(ticks: u64) {
+    let wake_at = crate::interrupts::ticks() + ticks;
+
+    while crate::interrupts::ticks() < wake_at {
+        interrupts::without_interrupts(|| {
+            let mut scheduler = scheduler().lock();
+            scheduler.sleep_current(wake_at);
+            scheduler::switch(scheduler, false);
+        });
+    }
+}
+
+/// An owned permission to join a kernel thread.
+///
+/// Dropping the handle detaches the thread, which keeps running.
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<spin::Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The ID of the thread.
+    pub fn id(&self) -> ThreadId {
+        self.thread.id
+    }
+
+    /// Returns `true` if the thread has finished running.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Block the current thread until the thread finishes, and return its
+    /// result.
+    ///
+    /// # Panics
+    /// Panics if a thread tries to join itself, or if called on an AP.
+    pub fn join(self) -> T {
+        while !self.thread.is_finished() {
+            interrupts::without_interrupts(|| {
+                let mut scheduler = scheduler().lock();
+                if self.thread.is_finished() {
+                    return;
+                }
+                assert!(
+                    !Arc::ptr_eq(scheduler.current(), &self.thread),
+                    "thread tried to join itself"
+                );
+
+                scheduler.join_current(&self.thread);
+                scheduler::switch(scheduler, false);
+            });
+        }
+
+        self.result
+            .lock()
+            .take()
+            .expect("finished thread left no result")
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("id", &self.id())
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
@@ -0,0 +1,278 @@
+use super::{
+    context::{self, Stack},
+    ThreadId,
+};
+use crate::{percpu, task::TaskHeader};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    cell::UnsafeCell,
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+
+/// The scheduler, created the first time a thread is spawned or blocks.
+///
+/// The lock is only ever taken with interrupts disabled, so that the timer
+/// interrupt can't preempt a thread that holds it.
+static SCHEDULER: OnceCell<Mutex<Scheduler>> = OnceCell::uninit();
+
+/// Get the scheduler, creating it if needed. Must not be called from
+/// interrupt handlers, since creating the scheduler allocates.
+///
+/// # Panics
+/// Panics if called on an AP.
+pub(super) fn scheduler() -> &'static Mutex<Scheduler> {
+    assert_on_bsp();
+    SCHEDULER.get_or_init(|| Mutex::new(Scheduler::new()))
+}
+
+/// Threads only run on the BSP. The scheduler's `current` thread is the one
+/// running there, so switching threads on an AP would save its stack pointer
+/// into the BSP's thread.
+fn assert_on_bsp() {
+    assert!(
+        percpu::current().is_bsp(),
+        "kernel threads can only run on the BSP"
+    );
+}
+
+/// A kernel thread.
+pub(super) struct Thread {
+    pub(super) id: ThreadId,
+    /// The saved stack pointer while the thread isn't running.
+    rsp: UnsafeCell<usize>,
+    /// `None` for the boot thread, which runs on the bootloader's stack.
+    stack: UnsafeCell<Option<Stack>>,
+    /// The code to run, taken out once the thread starts.
+    entry: UnsafeCell<Option<Box<dyn FnOnce() + Send>>>,
+    /// The tick a sleeping thread wants to wake up at.
+    wake_at: AtomicU64,
+    finished: AtomicBool,
+    /// The CPU's `current_task` while the thread is switched out, so that a
+    /// task that was preempted in the middle of its poll isn't taken for the
+    /// current task of the other threads.
+    polled_task: AtomicPtr<TaskHeader>,
+    /// Threads waiting for this thread to finish.
+    joiners: UnsafeCell<Vec<Arc<Thread>>>,
+}
+
+// Everything in an `UnsafeCell` is only touched with the scheduler locked, or
+// by the thread itself before it starts or after it's gone.
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(entry: Option<Box<dyn FnOnce() + Send>>, stack: Option<Stack>) -> Self {
+        Thread {
+            id: ThreadId::new(),
+            rsp: UnsafeCell::new(0),
+            stack: UnsafeCell::new(stack),
+            entry: UnsafeCell::new(entry),
+            wake_at: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+            polled_task: AtomicPtr::new(ptr::null_mut()),
+            joiners: UnsafeCell::new(Vec::new()),
+        }
+    }
+
+    /// Create a thread that runs `entry` on a fresh stack.
+    pub(super) fn spawn(entry: Box<dyn FnOnce() + Send>) -> Arc<Self> {
+        let mut stack = Stack::new();
+        let rsp = stack.initial_rsp(thread_start);
+
+        let mut thread = Thread::new(Some(entry), Some(stack));
+        *thread.rsp.get_mut() = rsp;
+        Arc::new(thread)
+    }
+
+    /// Returns `true` once the thread's code has returned.
+    pub(super) fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+/// A round-robin scheduler for kernel threads.
+pub(super) struct Scheduler {
+    /// The thread that's running right now.
+    current: Arc<Thread>,
+    /// Runs whenever no other thread is ready. Never in `ready`.
+    idle: Arc<Thread>,
+    /// Threads waiting for their turn.
+    ready: VecDeque<Arc<Thread>>,
+    /// Threads waiting for `wake_at` to pass.
+    sleeping: Vec<Arc<Thread>>,
+    /// Finished threads whose stacks haven't been freed yet.
+    exited: Vec<Arc<Thread>>,
+    /// The number of live threads, not counting the idle thread.
+    threads: usize,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        // Whoever creates the scheduler becomes the boot thread.
+        let boot = Arc::new(Thread::new(None, None));
+        percpu::current()
+            .current_thread
+            .store(boot.id.as_u64(), Ordering::Relaxed);
+
+        Scheduler {
+            current: boot,
+            idle: Thread::spawn(Box::new(|| loop {
+                x86_64::instructions::hlt();
+            })),
+            ready: VecDeque::new(),
+            sleeping: Vec::new(),
+            exited: Vec::new(),
+            threads: 1,
+        }
+    }
+
+    /// The thread that's running right now.
+    pub(super) fn current(&self) -> &Arc<Thread> {
+        &self.current
+    }
+
+    /// Add a new thread to the back of the ready queue.
+    pub(super) fn add(&mut self, thread: Arc<Thread>) {
+        self.reap();
+
+        // Make room for every thread up front, so that moving threads around
+        // in `preempt` never allocates inside the interrupt handler.
+        self.threads += 1;
+        self.ready.reserve(self.threads);
+        self.sleeping.reserve(self.threads);
+        self.exited.reserve(self.threads);
+
+        self.ready.push_back(thread);
+    }
+
+    /// Put the current thread to sleep until `wake_at`.
+    pub(super) fn sleep_current(&mut self, wake_at: u64) {
+        self.current.wake_at.store(wake_at, Ordering::Relaxed);
+        self.sleeping.push(self.current.clone());
+    }
+
+    /// Make the current thread wait for `thread` to finish.
+    pub(super) fn join_current(&mut self, thread: &Thread) {
+        unsafe { (*thread.joiners.get()).push(self.current.clone()) };
+    }
+
+    /// Mark the current thread as finished and wake the threads joining it.
+    fn exit_current(&mut self) {
+        let current = self.current.clone();
+        current.finished.store(true, Ordering::Release);
+
+        let joiners = unsafe { mem::take(&mut *current.joiners.get()) };
+        self.ready.extend(joiners);
+
+        self.threads -= 1;
+        self.exited.push(current);
+    }
+
+    /// Free the stacks of finished threads.
+    fn reap(&mut self) {
+        for thread in self.exited.drain(..) {
+            // The thread isn't running anymore, so nobody else touches it.
+            unsafe { *thread.stack.get() = None };
+        }
+    }
+
+    /// Move sleeping threads whose time has come to the ready queue.
+    fn wake_sleepers(&mut self) {
+        let now = crate::interrupts::ticks();
+
+        let mut i = 0;
+        while i < self.sleeping.len() {
+            if self.sleeping[i].wake_at.load(Ordering::Relaxed) <= now {
+                let thread = self.sleeping.swap_remove(i);
+                self.ready.push_back(thread);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Switch to the next ready thread.
+///
+/// If `requeue` is `true`, the current thread goes to the back of the ready
+/// queue, and keeps running if no other thread is ready. Otherwise the caller
+/// must have put it somewhere else, like the sleeping list or a join list,
+/// and the idle thread takes over if nothing is ready.
+///
+/// Interrupts must be disabled. Returns once the current thread gets switched
+/// back to.
+pub(super) fn switch(mut scheduler: MutexGuard<'static, Scheduler>, requeue: bool) {
+    scheduler.wake_sleepers();
+
+    let is_idle = Arc::ptr_eq(&scheduler.current, &scheduler.idle);
+    let next = match scheduler.ready.pop_front() {
+        Some(next) => next,
+        None if requeue || is_idle => return,
+        None => scheduler.idle.clone(),
+    };
+
+    let previous = mem::replace(&mut scheduler.current, next);
+    let cpu = percpu::current();
+    cpu.current_thread
+        .store(scheduler.current.id.as_u64(), Ordering::Relaxed);
+    previous
+        .polled_task
+        .store(cpu.current_task.load(Ordering::Acquire), Ordering::Relaxed);
+    cpu.current_task.store(
+        scheduler.current.polled_task.load(Ordering::Relaxed),
+        Ordering::Release,
+    );
+    cpu.stats().count_context_switch();
+    if requeue && !is_idle {
+        scheduler.ready.push_back(previous.clone());
+    }
+
+    let old_rsp = previous.rsp.get();
+    let new_rsp = unsafe { *scheduler.current.rsp.get() };
+
+    // Every thread is kept alive by the scheduler while it's switched out, so
+    // we must not hold on to any `Arc`s on a stack that might never resume.
+    drop(previous);
+    drop(scheduler);
+
+    unsafe { context::switch(old_rsp, new_rsp) };
+}
+
+/// Called by the timer interrupt to give the next ready thread a turn.
+///
+/// Does nothing until the scheduler exists, and never allocates.
+///
+/// # Panics
+/// Panics if called on an AP.
+pub(crate) fn preempt() {
+    assert_on_bsp();
+    if let Ok(scheduler) = SCHEDULER.try_get() {
+        switch(scheduler.lock(), true);
+    }
+}
+
+/// Where every new thread starts, coming out of `switch` with interrupts
+/// disabled.
+extern "C" fn thread_start() -> ! {
+    use x86_64::instructions::interrupts;
+
+    let entry = {
+        let scheduler = scheduler().lock();
+        unsafe { (*scheduler.current.entry.get()).take() }
+    };
+
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    interrupts::disable();
+
+    let mut scheduler = scheduler().lock();
+    scheduler.exit_current();
+    switch(scheduler, false);
+
+    unreachable!("finished thread was switched back to");
+}
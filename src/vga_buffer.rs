@@ -1,22 +1,81 @@
-use core::fmt;
+mod ansi;
+mod cp437;
+
+use crate::{lock::IrqSafeMutex, serial::SERIAL1};
+use alloc::{collections::VecDeque, vec::Vec};
+use ansi::{Action, Erase, Parser, Style};
+use core::{fmt, ops::Range};
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
 
 lazy_static! {
     /// A global VGA buffer `Writer`.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
+        row_position: BUFFER_HEIGHT - 1,
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
+        style: Style::new(Color::Yellow, Color::Black),
+        parser: Parser::new(),
+        scrollback: None,
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     });
 }
 
 /// The height of the VGA buffer.
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 
 /// The width of the VGA buffer.
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// How many rows that scrolled off the top of the screen the scrollback
+/// keeps.
+const SCROLLBACK_ROWS: usize = 200;
+
+/// What characters without a CP437 glyph are shown as, a small square.
+const REPLACEMENT_BYTE: u8 = 0xfe;
+
+/// The CRT controller's index and data ports. Writing a register's number to
+/// the index port selects the register that the data port accesses.
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+
+// CRT controller registers.
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_END: u8 = 0x0b;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+/// Set in the cursor start register to hide the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+/// The scanline bits of the cursor start and end registers.
+const SCANLINE_MASK: u8 = 0x1f;
+
+/// The shape of the blinking hardware cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    /// A line below the character, like the BIOS sets it up.
+    Underline,
+    /// The lower half of the character cell.
+    HalfBlock,
+    /// The whole character cell.
+    Block,
+    /// The scanlines from `start` to `end` of the character cell, counted
+    /// from the top. A character is 16 scanlines high.
+    Scanlines { start: u8, end: u8 },
+}
+
+impl CursorShape {
+    /// The first and the last scanline the cursor covers.
+    fn scanlines(self) -> (u8, u8) {
+        match self {
+            CursorShape::Underline => (13, 14),
+            CursorShape::HalfBlock => (8, 15),
+            CursorShape::Block => (0, 15),
+            CursorShape::Scanlines { start, end } => (start, end),
+        }
+    }
+}
 
 /// All the possible VGA colors.
 ///
@@ -50,11 +109,11 @@ pub enum Color {
 /// are for the background `Color`.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
     /// Create a new VGA `ColorCode` from a foregound and a background `Color`.
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
@@ -70,20 +129,182 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+/// A rectangle of the screen, in characters.
+///
+/// The parts of a region that are off the screen are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub row: usize,
+    pub col: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn new(row: usize, col: usize, width: usize, height: usize) -> Self {
+        Region {
+            row,
+            col,
+            width,
+            height,
+        }
+    }
+
+    /// The whole screen.
+    pub fn screen() -> Self {
+        Region::new(0, 0, BUFFER_WIDTH, BUFFER_HEIGHT)
+    }
+
+    /// The part of the region that is on the screen.
+    fn clipped(self) -> Self {
+        let row = self.row.min(BUFFER_HEIGHT);
+        let col = self.col.min(BUFFER_WIDTH);
+        Region {
+            row,
+            col,
+            width: self.width.min(BUFFER_WIDTH - col),
+            height: self.height.min(BUFFER_HEIGHT - row),
+        }
+    }
+
+    fn rows(self) -> Range<usize> {
+        self.row..self.row + self.height
+    }
+
+    fn cols(self) -> Range<usize> {
+        self.col..self.col + self.width
+    }
+}
+
+/// The lines of a box drawn with `Writer::draw_box`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStyle {
+    Single,
+    Double,
+}
+
+/// The CP437 line drawing characters of a box.
+struct BoxChars {
+    top_left: u8,
+    top_right: u8,
+    bottom_left: u8,
+    bottom_right: u8,
+    horizontal: u8,
+    vertical: u8,
+}
+
+impl BoxStyle {
+    fn chars(self) -> BoxChars {
+        match self {
+            BoxStyle::Single => BoxChars {
+                top_left: 0xda,
+                top_right: 0xbf,
+                bottom_left: 0xc0,
+                bottom_right: 0xd9,
+                horizontal: 0xc4,
+                vertical: 0xb3,
+            },
+            BoxStyle::Double => BoxChars {
+                top_left: 0xc9,
+                top_right: 0xbb,
+                bottom_left: 0xc8,
+                bottom_right: 0xbc,
+                horizontal: 0xcd,
+                vertical: 0xba,
+            },
+        }
+    }
+}
+
+/// The contents of a region of the screen, see `Writer::save_region`.
+pub struct SavedRegion {
+    region: Region,
+    /// The characters row by row.
+    chars: Vec<ScreenChar>,
+}
+
+impl SavedRegion {
+    /// The saved region, without its parts that are off the screen.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+}
+
+/// The characters of a row of the screen.
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+/// The rows that scrolled off the top of the screen, and the view of them.
+struct Scrollback {
+    /// The rows, oldest first.
+    rows: VecDeque<Row>,
+    /// How many rows up the view is scrolled. At 0, the screen shows the
+    /// live output.
+    offset: usize,
+    /// The live screen while the view is scrolled up.
+    live: Vec<Row>,
+}
+
+/// Where a row of the screen comes from while the view is scrolled up by
+/// `offset` rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ViewRow {
+    /// The row of the scrollback at this index.
+    Scrollback(usize),
+    /// The row of the live screen at this index.
+    Live(usize),
+}
+
+impl ViewRow {
+    fn new(scrollback_len: usize, offset: usize, row: usize) -> Self {
+        let index = scrollback_len - offset + row;
+        if index < scrollback_len {
+            ViewRow::Scrollback(index)
+        } else {
+            ViewRow::Live(index - scrollback_len)
+        }
+    }
+}
+
 /// The VGA text buffer.
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+impl Buffer {
+    /// A copy of the characters in `row`.
+    fn row(&self, row: usize) -> Row {
+        let mut chars = [self.chars[row][0].read(); BUFFER_WIDTH];
+        for (screen_char, volatile) in chars.iter_mut().zip(self.chars[row].iter()) {
+            *screen_char = volatile.read();
+        }
+        chars
+    }
+}
+
 /// A utility for writing a buffer to a VGA screen.
+///
+/// Output goes into the last row and scrolls the screen up, unless an ANSI
+/// escape sequence moved the cursor to another row.
 pub struct Writer {
-    /// Keeps track of the current position in the last row.
+    /// The row that the output goes into.
+    row_position: usize,
+
+    /// Keeps track of the current position in the row.
     column_position: usize,
 
     /// The current color.
     color_code: ColorCode,
 
+    /// The colors selected with escape sequences, which `color_code` follows.
+    style: Style,
+
+    /// Finds the escape sequences in the strings that are written.
+    parser: Parser,
+
+    /// `None` until `enable_scrollback` is called, as it needs the heap.
+    scrollback: Option<Scrollback>,
+
     /// The VGA buffer. `'static` because the reference to the actual VGA buffer
     /// is valid for the program's lifetime.
     buffer: &'static mut Buffer,
@@ -93,6 +314,12 @@ impl Writer {
     /// Write a single [CP437](https://en.wikipedia.org/wiki/Code_page_437) byte
     /// to the VGA buffer.
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.update_cursor();
+    }
+
+    /// Write a byte without moving the hardware cursor.
+    fn put_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
             byte => {
@@ -100,36 +327,308 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
+                self.put_char(
+                    row,
+                    col,
+                    ScreenChar {
+                        ascii_character: byte,
+                        color_code,
+                    },
+                );
                 self.column_position += 1;
             }
         }
     }
 
-    /// Write a string of ASCII bytes to the VGA buffer.
+    /// Write a string to the VGA buffer, showing its characters with their
+    /// CP437 glyphs.
+    ///
+    /// ANSI escape sequences that move the cursor, erase the line or the
+    /// screen and set the colors are interpreted, other ones are skipped.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // Printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // Not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+        for character in s.chars() {
+            if let Some(action) = self.parser.advance(character) {
+                self.perform(action);
+            }
+        }
+        self.update_cursor();
+    }
+
+    fn perform(&mut self, action: Action) {
+        let last_row = BUFFER_HEIGHT - 1;
+        let last_col = BUFFER_WIDTH - 1;
+
+        match action {
+            Action::Print('\n') => self.new_line(),
+            Action::Print('\r') => self.column_position = 0,
+            Action::Print(character) => self.put_byte(glyph(character)),
+            Action::CursorUp(count) => {
+                self.row_position = self.row_position.saturating_sub(count);
+            }
+            Action::CursorDown(count) => {
+                self.row_position = (self.row_position + count).min(last_row);
+            }
+            Action::CursorForward(count) => {
+                self.column_position = (self.column_position + count).min(last_col);
+            }
+            Action::CursorBack(count) => {
+                self.column_position = self.column_position.saturating_sub(count);
+            }
+            Action::CursorColumn(col) => self.column_position = col.min(last_col),
+            Action::CursorPosition { row, col } => {
+                self.row_position = row.min(last_row);
+                self.column_position = col.min(last_col);
+            }
+            Action::EraseLine(erase) => {
+                let cols = match erase {
+                    Erase::ToEnd => self.column_position..BUFFER_WIDTH,
+                    Erase::ToStart => 0..(self.column_position + 1).min(BUFFER_WIDTH),
+                    Erase::All => 0..BUFFER_WIDTH,
+                };
+                let region = Region::new(self.row_position, cols.start, cols.len(), 1);
+                self.clear_region(region, self.color_code);
+            }
+            Action::EraseDisplay(Erase::All) => {
+                self.clear_region(Region::screen(), self.color_code);
+            }
+            Action::EraseDisplay(erase) => {
+                let rows = match erase {
+                    Erase::ToStart => 0..self.row_position,
+                    _ => self.row_position + 1..BUFFER_HEIGHT,
+                };
+                let region = Region::new(rows.start, 0, BUFFER_WIDTH, rows.len());
+                self.clear_region(region, self.color_code);
+                self.perform(Action::EraseLine(erase));
+            }
+            Action::SetGraphics(params) => {
+                self.style.apply(params);
+                self.color_code = self.style.color_code();
+            }
+        }
+    }
+
+    /// Write a single CP437 byte at `row` and `col` in `color`, independent
+    /// of where the output goes. Nothing happens if it's off the screen.
+    pub fn write_byte_at(&mut self, row: usize, col: usize, byte: u8, color: ColorCode) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.put_char(
+                row,
+                col,
+                ScreenChar {
+                    ascii_character: byte,
+                    color_code: color,
+                },
+            );
+        }
+    }
+
+    /// Write a string starting at `row` and `col` in `color`. It's cut off at
+    /// the end of the row.
+    ///
+    /// Returns the column after the last character written.
+    pub fn write_str_at(&mut self, row: usize, col: usize, s: &str, color: ColorCode) -> usize {
+        let mut col = col;
+        for character in s.chars() {
+            if col >= BUFFER_WIDTH {
+                break;
+            }
+            self.write_byte_at(row, col, glyph(character), color);
+            col += 1;
+        }
+        col
+    }
+
+    /// The CP437 byte and the color at `row` and `col`, if that's on the
+    /// screen.
+    pub fn char_at(&self, row: usize, col: usize) -> Option<(u8, ColorCode)> {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            let screen_char = self.live_char(row, col);
+            Some((screen_char.ascii_character, screen_char.color_code))
+        } else {
+            None
+        }
+    }
+
+    /// Fill `region` with the CP437 `byte` in `color`.
+    pub fn fill_region(&mut self, region: Region, byte: u8, color: ColorCode) {
+        let region = region.clipped();
+        for row in region.rows() {
+            for col in region.cols() {
+                self.write_byte_at(row, col, byte, color);
             }
         }
     }
 
+    /// Clear `region` to the background of `color`.
+    pub fn clear_region(&mut self, region: Region, color: ColorCode) {
+        self.fill_region(region, b' ', color);
+    }
+
+    /// Draw the outline of `region` with line drawing characters, leaving
+    /// the inside as it is. Regions that are less than two characters wide
+    /// or high are too small for a box and left alone.
+    pub fn draw_box(&mut self, region: Region, style: BoxStyle, color: ColorCode) {
+        if region.width < 2 || region.height < 2 {
+            return;
+        }
+
+        let chars = style.chars();
+        let top = region.row;
+        let bottom = region.row + region.height - 1;
+        let left = region.col;
+        let right = region.col + region.width - 1;
+
+        for col in left + 1..right {
+            self.write_byte_at(top, col, chars.horizontal, color);
+            self.write_byte_at(bottom, col, chars.horizontal, color);
+        }
+        for row in top + 1..bottom {
+            self.write_byte_at(row, left, chars.vertical, color);
+            self.write_byte_at(row, right, chars.vertical, color);
+        }
+        self.write_byte_at(top, left, chars.top_left, color);
+        self.write_byte_at(top, right, chars.top_right, color);
+        self.write_byte_at(bottom, left, chars.bottom_left, color);
+        self.write_byte_at(bottom, right, chars.bottom_right, color);
+    }
+
+    /// Copy the contents of `region`, to put them back with
+    /// `restore_region` later, for example after showing a popup over them.
+    pub fn save_region(&self, region: Region) -> SavedRegion {
+        let region = region.clipped();
+        let mut chars = Vec::with_capacity(region.width * region.height);
+        for row in region.rows() {
+            for col in region.cols() {
+                chars.push(self.live_char(row, col));
+            }
+        }
+        SavedRegion { region, chars }
+    }
+
+    /// Put back the contents of a region saved with `save_region`.
+    pub fn restore_region(&mut self, saved: &SavedRegion) {
+        let region = saved.region;
+        let rows = saved.chars.chunks(region.width.max(1));
+        for (row, chars) in region.rows().zip(rows) {
+            for (col, &screen_char) in region.cols().zip(chars) {
+                self.put_char(row, col, screen_char);
+            }
+        }
+    }
+
+    /// The column that the next character goes into.
+    pub fn column_position(&self) -> usize {
+        self.column_position
+    }
+
+    /// Continue writing at `column` in the current row, overwriting what's
+    /// there.
+    pub fn set_column_position(&mut self, column: usize) {
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.update_cursor();
+    }
+
+    /// Clear the current row from the current column on.
+    pub fn clear_rest_of_row(&mut self) {
+        self.perform(Action::EraseLine(Erase::ToEnd));
+    }
+
+    /// Clear the whole screen and start over at the beginning of the last
+    /// row.
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row_position = BUFFER_HEIGHT - 1;
+        self.column_position = 0;
+        self.update_cursor();
+    }
+
+    /// Show or hide the hardware cursor.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        let start = self.read_crtc(CURSOR_START);
+        let start = if visible {
+            start & !CURSOR_DISABLE
+        } else {
+            start | CURSOR_DISABLE
+        };
+        self.write_crtc(CURSOR_START, start);
+    }
+
+    /// Change the shape of the hardware cursor. A hidden cursor stays hidden.
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        let (start, end) = shape.scanlines();
+        let start_register = self.read_crtc(CURSOR_START);
+        let end_register = self.read_crtc(CURSOR_END);
+        self.write_crtc(
+            CURSOR_START,
+            start_register & !SCANLINE_MASK | start & SCANLINE_MASK,
+        );
+        self.write_crtc(
+            CURSOR_END,
+            end_register & !SCANLINE_MASK | end & SCANLINE_MASK,
+        );
+    }
+
+    /// Move the hardware cursor to where the next character goes. After a
+    /// full row, it stays on the last column.
+    fn update_cursor(&mut self) {
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let position = self.row_position * BUFFER_WIDTH + col;
+        self.write_crtc(CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        self.write_crtc(CURSOR_LOCATION_LOW, position as u8);
+    }
+
+    /// Read a CRT controller register. This takes `&mut self` so that the
+    /// `WRITER` lock keeps the register selection and the access together.
+    fn read_crtc(&mut self, register: u8) -> u8 {
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        unsafe {
+            index.write(register);
+            data.read()
+        }
+    }
+
+    /// Write a CRT controller register.
+    fn write_crtc(&mut self, register: u8, value: u8) {
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        unsafe {
+            index.write(register);
+            data.write(value);
+        }
+    }
+
     fn new_line(&mut self) {
+        // Below a row the cursor was moved to, there's room without
+        // scrolling.
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            self.column_position = 0;
+            return;
+        }
+
+        let top = self.live_row(0);
+        if let Some(scrollback) = &mut self.scrollback {
+            if scrollback.rows.len() == SCROLLBACK_ROWS {
+                scrollback.rows.pop_front();
+            }
+            scrollback.rows.push_back(top);
+            // Keep a scrolled up view on the rows it shows.
+            if scrollback.offset > 0 {
+                scrollback.offset = (scrollback.offset + 1).min(scrollback.rows.len());
+            }
+        }
+
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+                let character = self.live_char(row, col);
+                self.put_char(row - 1, col, character);
             }
         }
 
@@ -137,6 +636,135 @@ impl Writer {
         self.column_position = 0;
     }
 
+    /// Keep the rows that scroll off the top of the screen, so that they can
+    /// be shown again with `scroll_up` and written out with
+    /// `dump_scrollback`.
+    ///
+    /// This allocates on the heap, so the heap has to be set up.
+    pub fn enable_scrollback(&mut self) {
+        if self.scrollback.is_none() {
+            self.scrollback = Some(Scrollback {
+                rows: VecDeque::with_capacity(SCROLLBACK_ROWS),
+                offset: 0,
+                live: Vec::with_capacity(BUFFER_HEIGHT),
+            });
+        }
+    }
+
+    /// Show the `count` rows above what's shown, as far as the scrollback
+    /// goes. The output keeps going to the live screen without moving the
+    /// view, until `scroll_down` or `scroll_to_bottom` shows it again.
+    pub fn scroll_up(&mut self, count: usize) {
+        let scrollback = match &mut self.scrollback {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+
+        if scrollback.offset == 0 {
+            scrollback.live.clear();
+            for row in 0..BUFFER_HEIGHT {
+                scrollback.live.push(self.buffer.row(row));
+            }
+        }
+        scrollback.offset = (scrollback.offset + count).min(scrollback.rows.len());
+        self.show_view();
+    }
+
+    /// Show the `count` rows below what's shown, as far as the live output.
+    pub fn scroll_down(&mut self, count: usize) {
+        match &mut self.scrollback {
+            Some(scrollback) if scrollback.offset > 0 => {
+                scrollback.offset = scrollback.offset.saturating_sub(count);
+            }
+            _ => return,
+        }
+        // At an offset of 0, this puts the live screen back.
+        self.show_view();
+    }
+
+    /// Show the live output again after `scroll_up`.
+    pub fn scroll_to_bottom(&mut self) {
+        if let Some(offset) = self.scrollback.as_ref().map(|scrollback| scrollback.offset) {
+            if offset > 0 {
+                self.scroll_down(offset);
+            }
+        }
+    }
+
+    /// Write the rows of the scrollback and then the live screen to `out` as
+    /// text, one line per row, without the spaces at their ends.
+    pub fn dump_scrollback(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let mut index = 0;
+        while let Some(row) = self.dump_row(index) {
+            write_row_text(out, &row)?;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// A copy of the row at `index` of the scrollback followed by the live
+    /// screen, or `None` past the end.
+    fn dump_row(&self, index: usize) -> Option<Row> {
+        let scrollback_len = self
+            .scrollback
+            .as_ref()
+            .map_or(0, |scrollback| scrollback.rows.len());
+        if index < scrollback_len {
+            self.scrollback
+                .as_ref()
+                .map(|scrollback| scrollback.rows[index])
+        } else if index - scrollback_len < BUFFER_HEIGHT {
+            Some(self.live_row(index - scrollback_len))
+        } else {
+            None
+        }
+    }
+
+    /// Draw the rows that the scrollback's offset selects.
+    fn show_view(&mut self) {
+        let scrollback = match &self.scrollback {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+
+        for row in 0..BUFFER_HEIGHT {
+            let chars = match ViewRow::new(scrollback.rows.len(), scrollback.offset, row) {
+                ViewRow::Scrollback(index) => &scrollback.rows[index],
+                ViewRow::Live(index) => &scrollback.live[index],
+            };
+            for (col, &screen_char) in chars.iter().enumerate() {
+                self.buffer.chars[row][col].write(screen_char);
+            }
+        }
+    }
+
+    /// The character at `row` and `col` of the live screen, even if the
+    /// scrollback is shown.
+    fn live_char(&self, row: usize, col: usize) -> ScreenChar {
+        match &self.scrollback {
+            Some(scrollback) if scrollback.offset > 0 => scrollback.live[row][col],
+            _ => self.buffer.chars[row][col].read(),
+        }
+    }
+
+    /// A copy of `row` of the live screen, even if the scrollback is shown.
+    fn live_row(&self, row: usize) -> Row {
+        match &self.scrollback {
+            Some(scrollback) if scrollback.offset > 0 => scrollback.live[row],
+            _ => self.buffer.row(row),
+        }
+    }
+
+    /// Write the character at `row` and `col` of the live screen. While the
+    /// scrollback is shown, that's the copy kept of it, and the view stays
+    /// as it is.
+    fn put_char(&mut self, row: usize, col: usize, screen_char: ScreenChar) {
+        match &mut self.scrollback {
+            Some(scrollback) if scrollback.offset > 0 => scrollback.live[row][col] = screen_char,
+            _ => self.buffer.chars[row][col].write(screen_char),
+        }
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
             ascii_character: b' ',
@@ -144,11 +772,46 @@ impl Writer {
         };
 
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.put_char(row, col, blank);
         }
     }
 }
 
+/// Write the characters of `row` as a line of text.
+fn write_row_text(out: &mut dyn fmt::Write, row: &Row) -> fmt::Result {
+    let len = row
+        .iter()
+        .rposition(|screen_char| cp437::to_char(screen_char.ascii_character) != ' ')
+        .map_or(0, |last| last + 1);
+    for screen_char in &row[..len] {
+        out.write_char(cp437::to_char(screen_char.ascii_character))?;
+    }
+    out.write_char('\n')
+}
+
+/// Write the scrollback and the screen to the first serial port, for example
+/// to keep the boot messages.
+pub fn dump_scrollback_to_serial() {
+    // Sending it all takes a while, and both locks keep interrupts disabled.
+    // So they're only held for one row at a time. Rows that scroll off in
+    // the meantime can be left out or sent twice.
+    let mut index = 0;
+    loop {
+        let row = match WRITER.lock().dump_row(index) {
+            Some(row) => row,
+            None => break,
+        };
+        // Writing to the serial port can't fail.
+        let _ = write_row_text(&mut *SERIAL1.lock(), &row);
+        index += 1;
+    }
+}
+
+/// The CP437 byte that shows `character`.
+fn glyph(character: char) -> u8 {
+    cp437::from_char(character).unwrap_or(REPLACEMENT_BYTE)
+}
+
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
@@ -156,13 +819,13 @@ impl fmt::Write for Writer {
     }
 }
 
-/// Print some ASCII text to the VGA buffer.
+/// Print some text to the VGA buffer.
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
 }
 
-/// Print some ASCII text to the VGA buffer, followed by a newline.
+/// Print some text to the VGA buffer, followed by a newline.
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
@@ -172,14 +835,8 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Lock the writer in an environment whwere interrupts are disabled to avoid
-    // deadlocks if an interrupt tries to print something while the global writer
-    // is locked.
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[cfg(test)]
@@ -201,17 +858,165 @@ mod tests {
     #[test_case]
     fn test_println_output() {
         use core::fmt::Write;
-        use x86_64::instructions::interrupts;
 
         let s = "Some test string that fits on a single line";
 
-        interrupts::without_interrupts(|| {
-            let mut writer = WRITER.lock();
-            writeln!(writer, "\n{}", s).expect("writeln failed");
-            for (i, c) in s.chars().enumerate() {
-                let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-                assert_eq!(char::from(screen_char.ascii_character), c);
+        // The lock keeps the timer interrupt from printing in between.
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    }
+
+    #[test_case]
+    fn test_write_str_at() {
+        let color = ColorCode::new(Color::White, Color::Blue);
+        let mut writer = WRITER.lock();
+        let end = writer.write_str_at(3, BUFFER_WIDTH - 4, "status\n", color);
+        assert_eq!(end, BUFFER_WIDTH);
+        assert_eq!(writer.char_at(3, BUFFER_WIDTH - 4), Some((b's', color)));
+        assert_eq!(writer.char_at(3, BUFFER_WIDTH - 1), Some((b't', color)));
+        assert_eq!(writer.char_at(3, BUFFER_WIDTH), None);
+
+        writer.write_str_at(BUFFER_HEIGHT, 0, "off the screen", color);
+        assert_eq!(writer.write_str_at(0, 0, "\t", color), 1);
+        assert_eq!(writer.char_at(0, 0), Some((REPLACEMENT_BYTE, color)));
+    }
+
+    #[test_case]
+    fn test_unicode() {
+        use core::fmt::Write;
+
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\né → ╔ π €").expect("writeln failed");
+        let mut bytes = [0; 9];
+        read_row(&writer, BUFFER_HEIGHT - 2, 0, &mut bytes);
+        assert_eq!(
+            bytes,
+            [0x82, b' ', 0x1a, b' ', 0xc9, b' ', 0xe3, b' ', 0xfe]
+        );
+
+        let color = ColorCode::new(Color::White, Color::Black);
+        assert_eq!(writer.write_str_at(0, 0, "½°", color), 2);
+        assert_eq!(writer.char_at(0, 1), Some((0xf8, color)));
+    }
+
+    #[test_case]
+    fn test_draw_box() {
+        let color = ColorCode::new(Color::LightGray, Color::Black);
+        let mut writer = WRITER.lock();
+        writer.clear_region(Region::new(5, 10, 4, 3), color);
+        writer.draw_box(Region::new(5, 10, 4, 3), BoxStyle::Double, color);
+
+        let row = |writer: &Writer, row| {
+            let mut bytes = [0; 4];
+            for (col, byte) in bytes.iter_mut().enumerate() {
+                *byte = writer.char_at(row, 10 + col).unwrap().0;
             }
-        });
+            bytes
+        };
+        assert_eq!(row(&writer, 5), [0xc9, 0xcd, 0xcd, 0xbb]);
+        assert_eq!(row(&writer, 6), [0xba, b' ', b' ', 0xba]);
+        assert_eq!(row(&writer, 7), [0xc8, 0xcd, 0xcd, 0xbc]);
+    }
+
+    #[test_case]
+    fn test_scrollback_view() {
+        // Scrolled up by 3 rows with 10 rows in the scrollback.
+        assert_eq!(ViewRow::new(10, 3, 0), ViewRow::Scrollback(7));
+        assert_eq!(ViewRow::new(10, 3, 2), ViewRow::Scrollback(9));
+        assert_eq!(ViewRow::new(10, 3, 3), ViewRow::Live(0));
+        assert_eq!(
+            ViewRow::new(10, 3, BUFFER_HEIGHT - 1),
+            ViewRow::Live(BUFFER_HEIGHT - 4)
+        );
+        assert_eq!(ViewRow::new(10, 0, 0), ViewRow::Live(0));
+    }
+
+    #[test_case]
+    fn test_regions_are_clipped() {
+        let region = Region::new(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 2, 5, 5).clipped();
+        assert_eq!(
+            region,
+            Region::new(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 2, 2, 1)
+        );
+        let region = Region::new(BUFFER_HEIGHT + 1, 0, 5, 5).clipped();
+        assert!(region.rows().is_empty());
+    }
+
+    /// The bytes in `row` from `col` on, as far as `bytes` goes.
+    fn read_row(writer: &Writer, row: usize, col: usize, bytes: &mut [u8]) {
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = writer.char_at(row, col + offset).unwrap().0;
+        }
+    }
+
+    #[test_case]
+    fn test_escape_sequences() {
+        use core::fmt::Write;
+
+        let mut bytes = [0; 5];
+        let mut writer = WRITER.lock();
+        write!(writer, "\x1b[2J\x1b[3;5Habcde\x1b[3D\x1b[K").expect("write failed");
+        read_row(&writer, 2, 4, &mut bytes);
+        assert_eq!(&bytes, b"ab   ");
+
+        write!(writer, "\x1b[1;31mX\x1b[0m\x1b[2AY").expect("write failed");
+        let red = ColorCode::new(Color::LightRed, Color::Black);
+        assert_eq!(writer.char_at(2, 6), Some((b'X', red)));
+        assert_eq!(writer.char_at(0, 7).unwrap().0, b'Y');
+        assert_eq!(
+            writer.color_code,
+            ColorCode::new(Color::Yellow, Color::Black)
+        );
+
+        // Without scrolling, a newline goes to the start of the next row.
+        write!(writer, "\r\nZ").expect("write failed");
+        assert_eq!(writer.char_at(1, 0).unwrap().0, b'Z');
+
+        write!(writer, "\x1b[4;1H\x1b[1J\x1b[25;1H").expect("write failed");
+        read_row(&writer, 2, 4, &mut bytes);
+        assert_eq!(&bytes, b"     ");
+        assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+    }
+
+    fn cursor_position(writer: &mut Writer) -> usize {
+        usize::from(writer.read_crtc(CURSOR_LOCATION_HIGH)) << 8
+            | usize::from(writer.read_crtc(CURSOR_LOCATION_LOW))
+    }
+
+    #[test_case]
+    fn test_cursor_follows_output() {
+        use core::fmt::Write;
+
+        let last_row = (BUFFER_HEIGHT - 1) * BUFFER_WIDTH;
+        let mut writer = WRITER.lock();
+        write!(writer, "\nabc").expect("write failed");
+        assert_eq!(cursor_position(&mut writer), last_row + 3);
+
+        writer.set_column_position(BUFFER_WIDTH);
+        assert_eq!(cursor_position(&mut writer), last_row + BUFFER_WIDTH - 1);
+        writeln!(writer).expect("writeln failed");
+        assert_eq!(cursor_position(&mut writer), last_row);
+    }
+
+    #[test_case]
+    fn test_cursor_shape_and_visibility() {
+        let mut writer = WRITER.lock();
+        writer.set_cursor_visible(false);
+        writer.set_cursor_shape(CursorShape::Block);
+        assert_eq!(
+            writer.read_crtc(CURSOR_START) & CURSOR_DISABLE,
+            CURSOR_DISABLE
+        );
+        assert_eq!(writer.read_crtc(CURSOR_START) & SCANLINE_MASK, 0);
+        assert_eq!(writer.read_crtc(CURSOR_END) & SCANLINE_MASK, 15);
+
+        writer.set_cursor_shape(CursorShape::Underline);
+        writer.set_cursor_visible(true);
+        assert_eq!(writer.read_crtc(CURSOR_START) & CURSOR_DISABLE, 0);
+        assert_eq!(writer.read_crtc(CURSOR_START) & SCANLINE_MASK, 13);
     }
 }
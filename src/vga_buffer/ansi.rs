@@ -0,0 +1,412 @@
+//! A parser for the ANSI escape sequences that terminals understand.
+//!
+//! Only the control sequences (CSI, `ESC [`) that move the cursor, erase
+//! parts of the screen and select colors are interpreted. Other sequences
+//! are recognized so that they can be skipped, but do nothing.
+
+use super::{Color, ColorCode};
+
+const ESCAPE: char = '\u{1b}';
+
+/// The most parameters a control sequence can have. Further ones are
+/// ignored.
+const MAX_PARAMS: usize = 16;
+
+/// The numeric parameters of a control sequence, like the `1` and `31` in
+/// `ESC [ 1 ; 31 m`. Missing parameters are 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Self {
+        Params {
+            values: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    /// The parameter at `index`, or `default` if it's missing or 0.
+    fn get(&self, index: usize, default: u16) -> u16 {
+        match self.values[..self.len].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+}
+
+/// Which part of the line or of the screen an erase sequence clears,
+/// relative to the cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Erase {
+    /// From the cursor to the end, including the cursor.
+    ToEnd,
+    /// From the start to the cursor, including the cursor.
+    ToStart,
+    All,
+}
+
+impl Erase {
+    fn from_param(param: u16) -> Option<Self> {
+        match param {
+            0 => Some(Erase::ToEnd),
+            1 => Some(Erase::ToStart),
+            // 3 also clears the scrollback of some terminals.
+            2 | 3 => Some(Erase::All),
+            _ => None,
+        }
+    }
+}
+
+/// What the writer should do for the bytes fed to the `Parser`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Action {
+    /// Show a character that isn't part of an escape sequence.
+    Print(char),
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBack(usize),
+    /// Move the cursor to a column of its row, counted from 0.
+    CursorColumn(usize),
+    /// Move the cursor to a row and a column, counted from 0.
+    CursorPosition {
+        row: usize,
+        col: usize,
+    },
+    EraseLine(Erase),
+    EraseDisplay(Erase),
+    /// Select Graphic Rendition: change the colors.
+    SetGraphics(Params),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// After an `ESC`.
+    Escape,
+    /// In a control sequence, after `ESC [`.
+    Csi,
+}
+
+/// Turns characters into `Action`s, one character at a time.
+pub(super) struct Parser {
+    state: State,
+    params: Params,
+    /// Set if the sequence has a private marker like the `?` in `ESC [ ? 25
+    /// h`. None of them are supported.
+    private: bool,
+    /// Set if the sequence has more than `MAX_PARAMS` parameters.
+    too_many_params: bool,
+}
+
+impl Parser {
+    pub(super) const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            params: Params::new(),
+            private: false,
+            too_many_params: false,
+        }
+    }
+
+    /// Feed the next character. Returns the action to take, if the character
+    /// completes one.
+    pub(super) fn advance(&mut self, character: char) -> Option<Action> {
+        match (self.state, character) {
+            // An escape always starts a new sequence, even in the middle of
+            // one.
+            (_, ESCAPE) => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, character) => Some(Action::Print(character)),
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.params = Params::new();
+                self.private = false;
+                self.too_many_params = false;
+                None
+            }
+            // Other escape sequences are only two bytes long, like `ESC c`.
+            (State::Escape, _) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Csi, '0'..='9') => {
+                if !self.too_many_params {
+                    self.params.len = self.params.len.max(1);
+                    let value = &mut self.params.values[self.params.len - 1];
+                    *value = value
+                        .saturating_mul(10)
+                        .saturating_add(u16::from(character as u8 - b'0'));
+                }
+                None
+            }
+            (State::Csi, ';') => {
+                // The parameter before the first `;` is there, even if empty.
+                let len = self.params.len.max(1);
+                if len == MAX_PARAMS {
+                    self.too_many_params = true;
+                } else {
+                    self.params.len = len + 1;
+                }
+                None
+            }
+            (State::Csi, '<'..='?') => {
+                self.private = true;
+                None
+            }
+            (State::Csi, '@'..='~') => {
+                self.state = State::Ground;
+                if self.private {
+                    None
+                } else {
+                    self.dispatch(character)
+                }
+            }
+            // Intermediate characters and control characters in a sequence.
+            (State::Csi, _) => None,
+        }
+    }
+
+    /// The action of the control sequence ending with `final_character`.
+    fn dispatch(&self, final_character: char) -> Option<Action> {
+        let params = &self.params;
+        let count = usize::from(params.get(0, 1));
+        // Positions are counted from 1.
+        let position = |index| usize::from(params.get(index, 1)) - 1;
+
+        match final_character {
+            'A' => Some(Action::CursorUp(count)),
+            'B' => Some(Action::CursorDown(count)),
+            'C' => Some(Action::CursorForward(count)),
+            'D' => Some(Action::CursorBack(count)),
+            'G' => Some(Action::CursorColumn(position(0))),
+            'H' | 'f' => Some(Action::CursorPosition {
+                row: position(0),
+                col: position(1),
+            }),
+            'J' => Erase::from_param(params.get(0, 0)).map(Action::EraseDisplay),
+            'K' => Erase::from_param(params.get(0, 0)).map(Action::EraseLine),
+            'm' => Some(Action::SetGraphics(*params)),
+            _ => None,
+        }
+    }
+}
+
+/// The VGA colors of the eight ANSI colors, in the order of their numbers:
+/// black, red, green, yellow, blue, magenta, cyan and white.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
+/// The bright variants of `ANSI_COLORS`.
+const BRIGHT_ANSI_COLORS: [Color; 8] = [
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+/// The colors selected with SGR sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Style {
+    default_foreground: Color,
+    default_background: Color,
+    foreground: Color,
+    background: Color,
+    /// Bold text is shown in the bright variant of its color.
+    bold: bool,
+    /// Swaps the foreground and the background.
+    reverse: bool,
+}
+
+impl Style {
+    pub(super) const fn new(foreground: Color, background: Color) -> Self {
+        Style {
+            default_foreground: foreground,
+            default_background: background,
+            foreground,
+            background,
+            bold: false,
+            reverse: false,
+        }
+    }
+
+    /// Apply the parameters of an SGR sequence. Unsupported ones are
+    /// ignored.
+    pub(super) fn apply(&mut self, params: Params) {
+        // `ESC [ m` is the same as `ESC [ 0 m`.
+        if params.len == 0 {
+            self.reset();
+        }
+
+        for param in params.iter() {
+            match param {
+                0 => self.reset(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                30..=37 => self.foreground = ANSI_COLORS[usize::from(param - 30)],
+                39 => self.foreground = self.default_foreground,
+                40..=47 => self.background = ANSI_COLORS[usize::from(param - 40)],
+                49 => self.background = self.default_background,
+                90..=97 => self.foreground = BRIGHT_ANSI_COLORS[usize::from(param - 90)],
+                100..=107 => self.background = BRIGHT_ANSI_COLORS[usize::from(param - 100)],
+                _ => {}
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Style::new(self.default_foreground, self.default_background);
+    }
+
+    pub(super) fn color_code(&self) -> ColorCode {
+        let foreground = if self.bold {
+            ANSI_COLORS
+                .iter()
+                .position(|&color| color == self.foreground)
+                .map_or(self.foreground, |index| BRIGHT_ANSI_COLORS[index])
+        } else {
+            self.foreground
+        };
+
+        if self.reverse {
+            ColorCode::new(self.background, foreground)
+        } else {
+            ColorCode::new(foreground, self.background)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The actions for `text`, which must not be more than four.
+    fn parse(text: &str) -> [Option<Action>; 4] {
+        let mut parser = Parser::new();
+        let mut actions = [None; 4];
+        let mut count = 0;
+        for character in text.chars() {
+            if let Some(action) = parser.advance(character) {
+                actions[count] = Some(action);
+                count += 1;
+            }
+        }
+        actions
+    }
+
+    #[test_case]
+    fn cursor_movement() {
+        assert_eq!(
+            parse("\x1b[A\x1b[3B\x1b[0C\x1b[12D"),
+            [
+                Some(Action::CursorUp(1)),
+                Some(Action::CursorDown(3)),
+                Some(Action::CursorForward(1)),
+                Some(Action::CursorBack(12)),
+            ]
+        );
+        assert_eq!(
+            parse("\x1b[H\x1b[5;10H\x1b[;7f\x1b[4G"),
+            [
+                Some(Action::CursorPosition { row: 0, col: 0 }),
+                Some(Action::CursorPosition { row: 4, col: 9 }),
+                Some(Action::CursorPosition { row: 0, col: 6 }),
+                Some(Action::CursorColumn(3)),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn erasing() {
+        assert_eq!(
+            parse("\x1b[K\x1b[1K\x1b[2J\x1b[5J"),
+            [
+                Some(Action::EraseLine(Erase::ToEnd)),
+                Some(Action::EraseLine(Erase::ToStart)),
+                Some(Action::EraseDisplay(Erase::All)),
+                None,
+            ]
+        );
+    }
+
+    #[test_case]
+    fn text_around_sequences() {
+        assert_eq!(
+            parse("a\x1b[?25lb\x1bcd"),
+            [
+                Some(Action::Print('a')),
+                Some(Action::Print('b')),
+                Some(Action::Print('d')),
+                None,
+            ]
+        );
+        // An escape in a sequence starts a new one.
+        assert_eq!(
+            parse("\x1b[3\x1b[2Ax"),
+            [
+                Some(Action::CursorUp(2)),
+                Some(Action::Print('x')),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test_case]
+    fn graphics() {
+        let mut parser = Parser::new();
+        let mut style = Style::new(Color::LightGray, Color::Black);
+        for character in "\x1b[1;31;44".chars() {
+            assert_eq!(parser.advance(character), None);
+        }
+        match parser.advance('m') {
+            Some(Action::SetGraphics(params)) => style.apply(params),
+            action => panic!("unexpected action {:?}", action),
+        }
+        assert_eq!(
+            style.color_code(),
+            ColorCode::new(Color::LightRed, Color::Blue)
+        );
+
+        let mut apply = |text: &str| {
+            for character in text.chars() {
+                if let Some(Action::SetGraphics(params)) = parser.advance(character) {
+                    style.apply(params);
+                }
+            }
+            style.color_code()
+        };
+        assert_eq!(apply("\x1b[22;7m"), ColorCode::new(Color::Blue, Color::Red));
+        assert_eq!(
+            apply("\x1b[27;39;49;93m"),
+            ColorCode::new(Color::Yellow, Color::Black)
+        );
+        assert_eq!(
+            apply("\x1b[m"),
+            ColorCode::new(Color::LightGray, Color::Black)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Mapping Unicode characters to [code page 437], the character set of the
+//! VGA text mode.
+//!
+//! [code page 437]: https://en.wikipedia.org/wiki/Code_page_437
+
+/// The characters shown for the bytes 0x00 to 0x1f. 0x00 is blank.
+const LOW: [char; 0x20] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The character shown for 0x7f.
+const HOUSE: char = '⌂';
+
+/// The characters shown for the bytes 0x80 to 0xff.
+const HIGH: [char; 0x80] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Characters without a glyph of their own that look like one in the code
+/// page.
+const LOOKALIKES: [(char, u8); 6] = [
+    // Beta, shown as the sharp s.
+    ('\u{3b2}', 0xe1),
+    // The Greek letter mu rather than the micro sign.
+    ('\u{3bc}', 0xe6),
+    // The n-ary summation sign rather than the letter sigma.
+    ('\u{2211}', 0xe4),
+    // The ohm sign rather than the letter omega.
+    ('\u{2126}', 0xea),
+    // Element of, shown as epsilon.
+    ('\u{2208}', 0xee),
+    // The empty set, shown as phi.
+    ('\u{2205}', 0xed),
+];
+
+/// The byte that shows `character`, if there's one. Control characters have
+/// none, as their bytes show symbols.
+pub(super) fn from_char(character: char) -> Option<u8> {
+    match character {
+        ' '..='~' => Some(character as u8),
+        HOUSE => Some(0x7f),
+        // Leave out the blank 0x00.
+        _ if character.is_control() => None,
+        _ => LOW
+            .iter()
+            .position(|&low| low == character)
+            .or_else(|| {
+                HIGH.iter()
+                    .position(|&high| high == character)
+                    .map(|index| index + 0x80)
+            })
+            .map(|index| index as u8)
+            .or_else(|| {
+                LOOKALIKES
+                    .iter()
+                    .find(|&&(lookalike, _)| lookalike == character)
+                    .map(|&(_, byte)| byte)
+            }),
+    }
+}
+
+/// The character that `byte` shows. 0x00 shows nothing, like a space.
+pub(super) fn to_char(byte: u8) -> char {
+    match byte {
+        0x00 => ' ',
+        0x01..=0x1f => LOW[usize::from(byte)],
+        0x7f => HOUSE,
+        0x80..=0xff => HIGH[usize::from(byte - 0x80)],
+        _ => char::from(byte),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ascii() {
+        assert_eq!(from_char('A'), Some(b'A'));
+        assert_eq!(from_char(' '), Some(b' '));
+        assert_eq!(from_char('~'), Some(b'~'));
+        assert_eq!(from_char('\u{7}'), None);
+        assert_eq!(from_char('\u{7f}'), None);
+    }
+
+    #[test_case]
+    fn symbols() {
+        assert_eq!(from_char('é'), Some(0x82));
+        assert_eq!(from_char('→'), Some(0x1a));
+        assert_eq!(from_char('═'), Some(0xcd));
+        assert_eq!(from_char('π'), Some(0xe3));
+        assert_eq!(from_char('■'), Some(0xfe));
+        assert_eq!(from_char('⌂'), Some(0x7f));
+        assert_eq!(from_char('\u{3b2}'), Some(0xe1));
+        assert_eq!(from_char('€'), None);
+        assert_eq!(from_char('\u{a0}'), Some(0xff));
+    }
+
+    #[test_case]
+    fn every_glyph_has_one_byte() {
+        for (index, &character) in HIGH.iter().enumerate() {
+            assert_eq!(from_char(character), Some(index as u8 + 0x80));
+        }
+        for (index, &character) in LOW.iter().enumerate().skip(1) {
+            assert_eq!(from_char(character), Some(index as u8));
+        }
+    }
+
+    #[test_case]
+    fn round_trip() {
+        for byte in 1..=0xff {
+            assert_eq!(from_char(to_char(byte)), Some(byte));
+        }
+        assert_eq!(to_char(0), ' ');
+    }
+}
@@ -0,0 +1,404 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    task::{
+        channel::{broadcast, mpsc, oneshot},
+        executor::Executor,
+        simple_executor::SimpleExecutor,
+        sync::{self, Barrier},
+        yield_now, AbortHandle, Builder, JoinError, JoinHandle, Priority, Task, WakeSource,
+    },
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_handle_returns_output() {
+    let mut executor = SimpleExecutor::new();
+    let result = Arc::new(Mutex::new(None));
+
+    let (task, handle) = Task::joinable(async { 6 * 7 });
+    executor.spawn(task);
+
+    let result_clone = result.clone();
+    executor.spawn(Task::new(async move {
+        *result_clone.lock() = Some(handle.await);
+    }));
+
+    executor.run();
+    assert_eq!(*result.lock(), Some(Ok(42)));
+}
+
+#[test_case]
+fn join_handle_reports_cancellation() {
+    let result = Arc::new(Mutex::new(None));
+
+    let (task, handle) = Task::joinable(async { 42 });
+    // Dropping the task without ever polling it cancels it.
+    core::mem::drop(task);
+
+    let mut executor = SimpleExecutor::new();
+    let result_clone = result.clone();
+    executor.spawn(Task::new(async move {
+        *result_clone.lock() = Some(handle.await);
+    }));
+
+    executor.run();
+    assert_eq!(*result.lock(), Some(Err(JoinError::Cancelled)));
+}
+
+#[test_case]
+fn abort_cancels_pending_task() {
+    let mut executor = SimpleExecutor::new();
+    let result = Arc::new(Mutex::new(None));
+
+    let (task, handle) = Task::joinable(futures_util::future::pending::<()>());
+    executor.spawn(task);
+
+    let result_clone = result.clone();
+    executor.spawn(Task::new(async move {
+        handle.abort();
+        *result_clone.lock() = Some(handle.await);
+    }));
+
+    executor.run();
+    assert_eq!(*result.lock(), Some(Err(JoinError::Cancelled)));
+}
+
+/// Run `executor` until `handle`'s task is done, and return its result.
+fn join_on<T: Send + 'static>(
+    executor: &mut Executor,
+    handle: JoinHandle<T>,
+) -> Result<T, JoinError> {
+    let result = Arc::new(Mutex::new(None));
+    let result_clone = result.clone();
+    executor.spawn(async move { *result_clone.lock() = Some(handle.await) });
+    for _ in 0..10 {
+        executor.run_once();
+    }
+    let result = result.lock().take();
+    result.expect("task didn't finish")
+}
+
+#[test_case]
+fn abort_cascades_to_children() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let child = Arc::new(Mutex::new(None));
+
+    let child_clone = child.clone();
+    let parent = executor.spawn(async move {
+        let handle = spawner.spawn(futures_util::future::pending::<()>());
+        *child_clone.lock() = Some(handle);
+        futures_util::future::pending::<()>().await;
+    });
+    executor.run_once();
+    let child = child.lock().take().expect("child wasn't spawned");
+
+    parent.abort_handle().abort();
+    assert_eq!(join_on(&mut executor, child), Err(JoinError::Cancelled));
+    assert_eq!(join_on(&mut executor, parent), Err(JoinError::Cancelled));
+}
+
+#[test_case]
+fn children_of_aborted_tasks_are_aborted() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let parent_abort: Arc<Mutex<Option<AbortHandle>>> = Arc::new(Mutex::new(None));
+    let child = Arc::new(Mutex::new(None));
+
+    let parent_abort_clone = parent_abort.clone();
+    let child_clone = child.clone();
+    let parent = executor.spawn(async move {
+        // The parent is aborted, but still running until it yields.
+        if let Some(abort) = parent_abort_clone.lock().take() {
+            abort.abort();
+        }
+        let handle = spawner.spawn(futures_util::future::pending::<()>());
+        *child_clone.lock() = Some(handle);
+        futures_util::future::pending::<()>().await;
+    });
+    *parent_abort.lock() = Some(parent.abort_handle());
+    executor.run_once();
+    let child = child.lock().take().expect("child wasn't spawned");
+
+    assert_eq!(join_on(&mut executor, child), Err(JoinError::Cancelled));
+    assert_eq!(join_on(&mut executor, parent), Err(JoinError::Cancelled));
+}
+
+#[test_case]
+fn async_mutex_is_exclusive() {
+    let mut executor = SimpleExecutor::new();
+    let counter = Arc::new(sync::Mutex::new(0));
+
+    for _ in 0..10 {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..10 {
+                let mut guard = counter.lock().await;
+                let value = *guard;
+                // Give everybody else a chance to sneak in.
+                yield_now().await;
+                *guard = value + 1;
+            }
+        }));
+    }
+
+    executor.run();
+    assert_eq!(*counter.try_lock().unwrap(), 100);
+}
+
+#[test_case]
+fn barrier_releases_tasks_in_groups() {
+    let mut executor = SimpleExecutor::new();
+    let barrier = Arc::new(Barrier::new(3));
+    let leaders = Arc::new(Mutex::new(0));
+
+    for _ in 0..6 {
+        let barrier = barrier.clone();
+        let leaders = leaders.clone();
+        executor.spawn(Task::new(async move {
+            if barrier.wait().await.is_leader() {
+                *leaders.lock() += 1;
+            }
+        }));
+    }
+
+    executor.run();
+    assert_eq!(*leaders.lock(), 2);
+}
+
+#[test_case]
+fn mpsc_channel_delivers_everything() {
+    let mut executor = SimpleExecutor::new();
+    let (sender, mut receiver) = mpsc::channel(4);
+    let (sum_sender, mut sum_receiver) = oneshot::channel();
+
+    for i in 0..3 {
+        let sender = sender.clone();
+        executor.spawn(Task::new(async move {
+            for j in 0..10 {
+                sender.send(i * 10 + j).await.expect("receiver dropped");
+            }
+        }));
+    }
+    drop(sender);
+
+    executor.spawn(Task::new(async move {
+        let mut sum = 0;
+        while let Some(value) = receiver.recv().await {
+            sum += value;
+        }
+        sum_sender.send(sum).expect("sum receiver dropped");
+    }));
+
+    executor.run();
+    assert_eq!(sum_receiver.try_recv(), Ok((0..30).sum()));
+}
+
+#[test_case]
+fn snapshot_lists_spawned_tasks() {
+    let mut executor = Executor::new();
+    let (task, handle) = Builder::new()
+        .name("sleeper")
+        .build(core::future::pending::<()>());
+    executor.spawn_task(task);
+    executor.spawn(async {});
+    // Tasks sent through a spawner only show up once the executor runs.
+    executor.spawner().spawn(async {});
+
+    let snapshot = executor.snapshot();
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[0].name.as_deref(), Some("sleeper"));
+    assert_eq!(snapshot[0].polls, 0);
+    assert_eq!(snapshot[0].last_polled_at, None);
+    assert_eq!(snapshot[1].name, None);
+    assert_eq!(
+        snapshot[1].last_wake.map(|(source, _)| source),
+        Some(WakeSource::Spawn)
+    );
+
+    core::mem::drop(handle);
+}
+
+#[test_case]
+fn dropping_executor_cancels_tasks() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(core::future::pending::<()>());
+    let (task, unspawned) = Task::joinable(async {});
+
+    core::mem::drop(executor);
+    core::mem::drop(task);
+    assert!(handle.is_finished());
+    assert!(unspawned.is_finished());
+}
+
+/// Counts its polls and never finishes. On its first poll, it wakes itself
+/// `wakeups` times.
+struct WakeRepeatedly {
+    wakeups: usize,
+    polls: Arc<AtomicUsize>,
+}
+
+impl Future for WakeRepeatedly {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.polls.fetch_add(1, Ordering::SeqCst) == 0 {
+            for _ in 0..self.wakeups {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn repeated_wakeups_queue_a_task_once() {
+    let mut executor = Executor::new();
+    let polls = Arc::new(AtomicUsize::new(0));
+    executor.spawn(WakeRepeatedly {
+        wakeups: 5,
+        polls: polls.clone(),
+    });
+
+    for _ in 0..3 {
+        executor.run_once();
+    }
+    // Once for the spawn and once for all of the wakeups.
+    assert_eq!(polls.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn many_wakeups_in_a_row_are_not_lost() {
+    const TASKS: usize = 250;
+
+    let mut executor = Executor::new();
+    let mut senders = Vec::new();
+    let mut handles = Vec::new();
+    for _ in 0..TASKS {
+        let (sender, receiver) = oneshot::channel();
+        senders.push(sender);
+        handles.push(executor.spawn(async move { receiver.await.is_ok() }));
+    }
+
+    // Poll every task once, so that they all wait for their value.
+    for _ in 0..TASKS {
+        executor.run_once();
+    }
+    assert!(handles.iter().all(|handle| !handle.is_finished()));
+
+    // Wake them all up without the executor getting a chance to run, like
+    // an interrupt handler could.
+    for sender in senders {
+        sender.send(()).expect("receiver dropped");
+    }
+    for _ in 0..TASKS {
+        executor.run_once();
+    }
+    assert!(handles.iter().all(|handle| handle.is_finished()));
+}
+
+#[test_case]
+fn interrupt_tasks_are_polled_first() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    for &(priority, name) in &[
+        (Priority::Background, 'b'),
+        (Priority::Normal, 'n'),
+        (Priority::Interrupt, 'i'),
+    ] {
+        let log = log.clone();
+        Builder::new()
+            .priority(priority)
+            .spawn_on(&spawner, async move { log.lock().push(name) });
+    }
+
+    executor.run_once();
+    assert_eq!(*log.lock(), ['i', 'n', 'b']);
+}
+
+#[test_case]
+fn yielding_tasks_do_not_starve_background_tasks() {
+    let mut executor = Executor::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    for _ in 0..3 {
+        let log = log.clone();
+        executor.spawn(async move {
+            loop {
+                log.lock().push('n');
+                yield_now().await;
+            }
+        });
+    }
+    let background_log = log.clone();
+    Builder::new()
+        .priority(Priority::Background)
+        .spawn_on(&executor.spawner(), async move {
+            background_log.lock().push('b')
+        });
+
+    // The normal tasks are always ready, but the round still ends and the
+    // background task gets its turn in it.
+    executor.run_once();
+    assert!(log.lock().contains(&'b'));
+    assert!(log.lock().len() > 3);
+}
+
+#[test_case]
+fn dropped_broadcast_receivers_are_not_woken() {
+    let mut executor = Executor::new();
+    let (sender, mut receiver) = broadcast::channel(4);
+    let _other_receiver = sender.subscribe();
+
+    executor.spawn(async move {
+        // Wait for a value once, then give up on it.
+        futures_util::future::poll_fn(|cx| {
+            assert!(receiver.poll_recv(cx).is_pending());
+            Poll::Ready(())
+        })
+        .await;
+        drop(receiver);
+        futures_util::future::pending::<()>().await;
+    });
+    executor.run_once();
+
+    sender.send(1).expect("no receivers");
+    executor.run_once();
+    assert_eq!(executor.snapshot()[0].polls, 1);
+}
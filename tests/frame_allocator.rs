@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{bootinfo::MemoryMap, entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, PhysFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+static mut MEMORY_MAP: Option<&'static MemoryMap> = None;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    unsafe { MEMORY_MAP = Some(&boot_info.memory_map) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// A second allocator over the memory map. Its frames are only compared,
+/// never used, since the first one already handed some of them out.
+fn allocator() -> BootInfoFrameAllocator {
+    unsafe { BootInfoFrameAllocator::init(MEMORY_MAP.expect("no memory map")) }
+}
+
+#[test_case]
+fn low_frame_is_below_1_mib() {
+    let mut allocator = allocator();
+    let frame = allocator.allocate_low_frame().expect("no low frame");
+    assert!(frame.start_address().as_u64() < 0x10_0000);
+    assert_eq!(allocator.allocate_low_frame(), None);
+}
+
+#[test_case]
+fn low_frame_is_handed_out_once() {
+    let mut allocator = allocator();
+    // Go past the low frame first, so that the frames after it are the ones
+    // that would shift if it came back into the sequence.
+    let mut frames: Vec<PhysFrame> = Vec::new();
+    loop {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        frames.push(frame);
+        if frame.start_address().as_u64() >= 0x10_0000 {
+            break;
+        }
+    }
+    frames.push(allocator.allocate_low_frame().expect("no low frame"));
+    for _ in 0..8 {
+        frames.push(allocator.allocate_frame().expect("out of frames"));
+    }
+
+    for (index, frame) in frames.iter().enumerate() {
+        assert!(!frames[index + 1..].contains(frame), "{:?} repeats", frame);
+    }
+}
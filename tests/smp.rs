@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    acpi, allocator, apic,
+    memory::{self, BootInfoFrameAllocator},
+    percpu, smp,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    unsafe {
+        PHYS_MEM_OFFSET = boot_info.physical_memory_offset;
+        smp::init(phys_mem_offset, &mut mapper, &mut frame_allocator).expect("SMP init failed");
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_every_cpu() {
+    // QEMU is started with `-smp 4`.
+    let madt = unsafe { acpi::parse_madt(VirtAddr::new(PHYS_MEM_OFFSET)) }.unwrap();
+    assert_eq!(madt.processors.len(), 4);
+}
+
+#[test_case]
+fn every_cpu_comes_online() {
+    assert_eq!(smp::cpu_count(), 4);
+}
+
+#[test_case]
+fn bsp_has_local_apic() {
+    let local_apic = apic::local_apic().expect("local APIC not initialized");
+    assert_eq!(local_apic.id(), 0);
+}
+
+#[test_case]
+fn bsp_per_cpu_data() {
+    let cpu = percpu::current();
+    assert_eq!(cpu.id(), 0);
+    assert!(cpu.is_bsp());
+    assert_eq!(cpu.apic_id(), 0);
+    assert!(cpu.tss().is_some());
+}
+
+#[test_case]
+fn per_cpu_interrupt_count() {
+    let stats = percpu::current().stats();
+    let before = stats.interrupts();
+
+    // Wait for a timer interrupt.
+    x86_64::instructions::hlt();
+    assert!(stats.interrupts() > before);
+}
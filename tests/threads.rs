@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use rust_os::{
+    allocator, interrupts,
+    memory::{self, BootInfoFrameAllocator},
+    thread,
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_result() {
+    let handle = thread::spawn_thread(|| 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn yield_now_interleaves_threads() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    // Spawn both before either of them gets a turn.
+    let handles: Vec<_> = x86_64::instructions::interrupts::without_interrupts(|| {
+        (0..2)
+            .map(|id| {
+                let log = log.clone();
+                thread::spawn_thread(move || {
+                    for step in 0..3 {
+                        log.lock().push((id, step));
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect()
+    });
+
+    for handle in handles {
+        handle.join();
+    }
+
+    // The timer can preempt them anywhere, so the exact order isn't fixed.
+    // But each thread's steps are in order, and neither thread finishes
+    // before the other one starts.
+    let log = log.lock();
+    assert_eq!(log.len(), 6);
+    for id in 0..2 {
+        let steps: Vec<_> = log
+            .iter()
+            .filter(|&&(thread_id, _)| thread_id == id)
+            .map(|&(_, step)| step)
+            .collect();
+        assert_eq!(steps, [0, 1, 2]);
+    }
+    let position = |entry: (i32, i32)| log.iter().position(|&logged| logged == entry).unwrap();
+    assert!(position((0, 0)) < position((1, 2)));
+    assert!(position((1, 0)) < position((0, 2)));
+}
+
+#[test_case]
+fn busy_threads_get_preempted() {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let handle = thread::spawn_thread(|| {
+        STARTED.store(true, Ordering::SeqCst);
+        let mut spins = 0u64;
+        while !STOP.load(Ordering::SeqCst) {
+            spins += 1;
+        }
+        spins
+    });
+
+    // Neither thread ever yields, so only the timer can get the other one to
+    // run.
+    while !STARTED.load(Ordering::SeqCst) {}
+    STOP.store(true, Ordering::SeqCst);
+
+    handle.join();
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    let start = interrupts::ticks();
+    let handle = thread::spawn_thread(|| {
+        thread::sleep(2);
+        WOKEN.fetch_add(1, Ordering::SeqCst);
+    });
+
+    handle.join();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 1);
+    assert!(interrupts::ticks() >= start + 2);
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    vga_buffer::{BoxStyle, Color, ColorCode, Region, BUFFER_WIDTH, WRITER},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn save_and_restore() {
+    let text = ColorCode::new(Color::Green, Color::Black);
+    let popup = ColorCode::new(Color::White, Color::Red);
+    let region = Region::new(2, 20, 10, 4);
+
+    let mut writer = WRITER.lock();
+    for row in region.row..region.row + region.height {
+        writer.write_str_at(row, region.col, "0123456789", text);
+    }
+
+    let saved = writer.save_region(region);
+    writer.clear_region(region, popup);
+    writer.draw_box(region, BoxStyle::Single, popup);
+    assert_eq!(writer.char_at(2, 20), Some((0xda, popup)));
+
+    writer.restore_region(&saved);
+    for row in region.row..region.row + region.height {
+        for (offset, digit) in (b'0'..=b'9').enumerate() {
+            assert_eq!(
+                writer.char_at(row, region.col + offset),
+                Some((digit, text))
+            );
+        }
+    }
+}
+
+#[test_case]
+fn save_clipped_region() {
+    let color = ColorCode::new(Color::Cyan, Color::Black);
+    let mut writer = WRITER.lock();
+    writer.write_str_at(0, BUFFER_WIDTH - 2, "ab", color);
+
+    let saved = writer.save_region(Region::new(0, BUFFER_WIDTH - 2, 10, 1));
+    assert_eq!(saved.region(), Region::new(0, BUFFER_WIDTH - 2, 2, 1));
+
+    writer.write_str_at(0, BUFFER_WIDTH - 2, "xy", color);
+    writer.restore_region(&saved);
+    assert_eq!(writer.char_at(0, BUFFER_WIDTH - 1), Some((b'b', color)));
+}
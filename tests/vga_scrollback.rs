@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{fmt::Write, panic::PanicInfo};
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH, WRITER},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    WRITER.lock().enable_scrollback();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// The text of `row` of the live screen.
+fn live_row(row: usize) -> String {
+    let writer = WRITER.lock();
+    (0..6)
+        .map(|col| char::from(writer.char_at(row, col).unwrap().0))
+        .collect()
+}
+
+/// The text shown in `row` of the screen, read straight from the VGA buffer.
+fn shown_row(row: usize) -> String {
+    let buffer = 0xb8000 as *const u16;
+    (0..6)
+        .map(|col| {
+            let screen_char =
+                unsafe { core::ptr::read_volatile(buffer.add(row * BUFFER_WIDTH + col)) };
+            char::from(screen_char as u8)
+        })
+        .collect()
+}
+
+#[test_case]
+fn scrolled_off_rows_are_kept() {
+    let mut writer = WRITER.lock();
+    for line in 0..BUFFER_HEIGHT * 2 {
+        writeln!(writer, "line{:02}", line).expect("writeln failed");
+    }
+
+    let mut dump = String::new();
+    writer.dump_scrollback(&mut dump).expect("dump failed");
+    let first = dump.find("line00\n").expect("line00 missing");
+    let last = dump.find("line49\n").expect("line49 missing");
+    assert!(first < last);
+    assert!(dump[first..].starts_with("line00\nline01\nline02\n"));
+}
+
+#[test_case]
+fn scrolling_keeps_the_live_screen() {
+    {
+        let mut writer = WRITER.lock();
+        for line in 0..BUFFER_HEIGHT * 2 {
+            writeln!(writer, "text{:02}", line).expect("writeln failed");
+        }
+        writer.scroll_up(10);
+    }
+    // The live screen is what the output goes to, even while the
+    // scrollback is shown.
+    assert_eq!(live_row(BUFFER_HEIGHT - 2), "text49");
+
+    let mut writer = WRITER.lock();
+    writer.scroll_down(4);
+    writer.scroll_up(1000);
+    let mut dump = String::new();
+    writer.dump_scrollback(&mut dump).expect("dump failed");
+    assert!(dump.ends_with("text49\n\n"));
+
+    write!(writer, "more").expect("write failed");
+    drop(writer);
+    assert_eq!(live_row(BUFFER_HEIGHT - 1), "more  ");
+}
+
+#[test_case]
+fn output_leaves_a_scrolled_up_view_alone() {
+    let mut writer = WRITER.lock();
+    writer.scroll_to_bottom();
+    for line in 0..BUFFER_HEIGHT * 2 {
+        writeln!(writer, "view{:02}", line).expect("writeln failed");
+    }
+    writer.scroll_up(10);
+    let shown: Vec<String> = (0..BUFFER_HEIGHT).map(shown_row).collect();
+
+    for line in 0..5 {
+        writeln!(writer, "late{}", line).expect("writeln failed");
+    }
+    assert!((0..BUFFER_HEIGHT).map(shown_row).eq(shown.iter().cloned()));
+    // Redrawing the view shows the same rows, even though more of them
+    // scrolled off the live screen.
+    writer.scroll_up(0);
+    assert!((0..BUFFER_HEIGHT).map(shown_row).eq(shown.iter().cloned()));
+    drop(writer);
+    assert_eq!(live_row(BUFFER_HEIGHT - 2), "late4 ");
+
+    WRITER.lock().scroll_to_bottom();
+    assert_eq!(shown_row(BUFFER_HEIGHT - 2), "late4 ");
+}